@@ -0,0 +1,191 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::block_in_place;
+use tokio::time::timeout;
+
+use super::config::{AcceptErrorPolicy, Config};
+use super::handle::{ConnectionGuard, ServerState};
+use super::server::{route_request, route_cors, finish_response, is_dropped, set_keep_alive, is_connection_upgrade, is_websocket_upgrade};
+use super::App;
+use crate::http::cors::CorsConfig;
+use crate::utils::log::*;
+use crate::context::http::HttpContext;
+use crate::context::ws::SocketContext;
+use crate::http::{entity::*, codes::HttpCode};
+use crate::http1_async::{AsyncHttp1Engine, AsyncHttp1Connection};
+use crate::websocket::{websocket_handshake, HandshakeResult, maintain_websocket_async};
+
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Serves connections by tokio runtime, idle keep-alive connections and WebSockets don't occupy threads
+pub(crate) fn start_server (app: Arc<App>, state: Arc<ServerState>) {
+    let config = Config::get();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(config.workers)
+        .enable_all()
+        .build();
+    match runtime {
+        Ok(runtime) => {
+            let signals = state.watch_signals();
+            if runtime.block_on(listen(app.clone(), state.clone())) {
+                log_info("Shutting down");
+                // Spawned tasks keep running on runtime threads while connections are drained
+                state.drain(config.shutdown_timeout, &app.channels);
+            }
+
+            runtime.shutdown_timeout(ACCEPT_POLL_INTERVAL);
+            state.unwatch_signals(signals);
+        }
+        Err(error) => log_error_lines("Runtime error", error.to_string())
+    }
+}
+
+/// Returns `false` if server wasn't started
+async fn listen (app: Arc<App>, state: Arc<ServerState>) -> bool {
+    let config = Config::get();
+    // Lazy policy initialization isn't synchronized, so it's done before spawning tasks
+    CorsConfig::get();
+
+    let bind_address = format!("{}:{}", config.host, config.port);
+
+    match TcpListener::bind(&bind_address).await {
+        Ok(listener) => {
+            log_success(&format!("Listening on {} (async)", bind_address));
+
+            while !state.is_stopping() {
+                // Accepting is interrupted periodically to notice shutdown
+                match timeout(ACCEPT_POLL_INTERVAL, listener.accept()).await {
+                    Ok(Ok(socket)) => {
+                        let guard = state.track(None);
+                        let app = app.clone();
+                        let state = state.clone();
+                        tokio::spawn(async move {
+                            proceed_connection::<AsyncHttp1Engine, AsyncHttp1Connection>(app, &state, &guard, socket).await;
+                        });
+                    }
+                    Ok(Err(error)) => {
+                        log_error_lines("Accept error", error.to_string());
+                        match config.on_accept_error {
+                            AcceptErrorPolicy::Retry => tokio::time::sleep(ACCEPT_POLL_INTERVAL).await,
+                            AcceptErrorPolicy::Shutdown => state.shutdown()
+                        }
+                    }
+                    Err(_) => {}
+                }
+            }
+
+            return true;
+        }
+        Err(error) => {
+            log_error_lines("Listen error", error.to_string());
+            return false;
+        }
+    }
+}
+
+async fn proceed_connection<Http: AsyncHttpEngine<Connection>, Connection: AsyncHttpConnection + 'static>
+(app: Arc<App>, state: &ServerState, guard: &ConnectionGuard, socket: (TcpStream, SocketAddr)) {
+    let mut connection = Http::handle_connection(socket);
+    let keep_alive = &Config::get().keep_alive;
+    let mut requests_count = 0usize;
+    connection.set_timeout(Some(keep_alive.timeout));
+
+    loop {
+        match connection.parse().await {
+            ParsingResult::Complete(req) => {
+                requests_count += 1;
+
+                if is_connection_upgrade(&req) {
+                    if is_websocket_upgrade(&req) {
+                        proceed_websocket(app, guard, connection, req).await;
+                        return;
+                    } else {
+                        let _ = connection.respond(Response::from_status(HttpCode::BadRequest)).await;
+                        break;
+                    }
+                }
+
+                let is_keep_alive = requests_count < keep_alive.max && !state.is_stopping() && connection.is_keep_alive(&req);
+                if !matches!(proceed_http(&app, &mut connection, req, is_keep_alive).await, Ok(true)) {
+                    break;
+                }
+            }
+            ParsingResult::Partial => break,
+            ParsingResult::Error(res_code) => {
+                let mut res = Response::from_status(res_code);
+                res.headers.set("connection".to_string(), "close".to_string());
+                let _ = connection.respond(res).await;
+                break;
+            }
+            ParsingResult::Invalid => break
+        }
+    }
+
+    let _ = connection.disconnect().await;
+}
+
+/// Returns `false` if connection can't be reused
+async fn proceed_http<Connection: AsyncHttpConnection> (app: &App, connection: &mut Connection, req: Request, is_keep_alive: bool) -> Result<bool, std::io::Error> {
+    let is_head = req.method == HttpMethod::HEAD;
+    let (route, params) = route_request(app, &req);
+    let cors = route_cors(app, &req, &route);
+    let mut ctx = HttpContext::new(connection.get_address(), req, params);
+    if let Some(cors) = cors { ctx.extensions.insert(cors); }
+    // Stores and middlewares may block on IO
+    let cookies = ctx.cookies.outgoing_handle();
+    let session = app.sessions.as_ref().map(|sessions| block_in_place(|| sessions.start(&mut ctx)));
+
+    let (pending, stopped) = block_in_place(|| app.middlewares.run(&mut ctx, route.as_ref().ok().copied()));
+    let mut res = match (stopped, route) {
+        (Some(res), _) | (None, Err(res)) => res,
+        (None, Ok(route)) => route.call_async(ctx).await
+    };
+
+    pending.finish(&mut res);
+    cookies.apply(&mut res);
+    if let (Some(sessions), Some(session)) = (&app.sessions, session) {
+        block_in_place(|| sessions.finish(session, &mut res));
+    }
+
+    let mut res = finish_response(res, is_head);
+    if is_dropped(&res) { return Ok(false); }
+
+    set_keep_alive(&mut res, is_keep_alive);
+    connection.respond(res).await?;
+    return Ok(is_keep_alive);
+}
+
+async fn proceed_websocket<Connection: AsyncHttpConnection>
+(app: Arc<App>, guard: &ConnectionGuard, mut connection: Connection, req: Request) {
+    match websocket_handshake(&app, &req) {
+        HandshakeResult::Ok(mut handshake, res) => {
+            if connection.respond(res).await.is_err() { return; }
+
+            let http = HttpContext::new(connection.get_address(), req, handshake.take_params());
+            let (stream, buffered) = connection.into_stream();
+            let (stream, socket) = match clone_socket(stream) {
+                Ok(pair) => pair,
+                Err(_) => return
+            };
+
+            if let Ok(socket) = socket.try_clone() { guard.set_stream(socket); }
+            let endpoint_index = handshake.endpoint;
+            let endpoint = &app.ws_endpoints.at(endpoint_index).unwrap().path;
+            let ctx = SocketContext::from_async(http, stream, socket, buffered, handshake, &app.channels, endpoint);
+            let _ = maintain_websocket_async(&app, ctx, endpoint_index).await;
+        }
+        HandshakeResult::Err(res) => {
+            let _ = connection.respond(res).await;
+            let _ = connection.disconnect().await;
+        }
+    }
+}
+
+/// Returns blocking handle of the same socket, it's used only to close connection from other threads
+fn clone_socket (stream: TcpStream) -> Result<(TcpStream, std::net::TcpStream), std::io::Error> {
+    let stream = stream.into_std()?;
+    let socket = stream.try_clone()?;
+    return Ok((TcpStream::from_std(stream)?, socket));
+}
@@ -1,4 +1,4 @@
-use std::{fs, process, env};
+use std::{fs, process, env, path::PathBuf, time::Duration};
 use json::JsonValue;
 use crate::utils::{log::log_error_lines, json_access};
 
@@ -7,7 +7,164 @@ pub struct Config {
 	obj: JsonValue,
 
 	pub host: String,
-	pub port: u16
+	pub port: u16,
+	pub limits: Limits,
+	pub keep_alive: KeepAlive,
+	pub websocket: WebSocketConfig,
+	pub session: SessionConfig,
+	pub cookies: CookieConfig,
+	/// Size of worker pool, for async engine it's count of runtime threads
+	pub workers: usize,
+	pub on_accept_error: AcceptErrorPolicy,
+	/// How long active connections are waited for during shutdown
+	pub shutdown_timeout: Duration
+}
+
+pub enum AcceptErrorPolicy {
+	/// Error is logged and accepting continues after short delay
+	Retry,
+	/// Error is logged and server is shut down gracefully
+	Shutdown
+}
+
+impl AcceptErrorPolicy {
+	fn from (obj: &JsonValue) -> Self {
+		match obj.as_str() {
+			Some("shutdown") => AcceptErrorPolicy::Shutdown,
+			_ => AcceptErrorPolicy::Retry
+		}
+	}
+}
+
+pub struct Limits {
+	/// Maximum size of request body in bytes
+	pub body: usize,
+	/// Maximum size of single `multipart/form-data` part in bytes
+	pub multipart_part: usize,
+	/// Maximum size of whole `multipart/form-data` body in bytes
+	pub multipart_total: usize,
+	/// Files bigger than this size are stored in temporary files instead of memory
+	pub multipart_memory: usize
+}
+
+impl Limits {
+	fn from (obj: &JsonValue) -> Self {
+		let multipart = &obj["multipart"];
+		Limits {
+			body: obj["body"].as_usize().unwrap_or(1024 * 1024),
+			multipart_part: multipart["part"].as_usize().unwrap_or(16 * 1024 * 1024),
+			multipart_total: multipart["total"].as_usize().unwrap_or(64 * 1024 * 1024),
+			multipart_memory: multipart["memory"].as_usize().unwrap_or(256 * 1024)
+		}
+	}
+}
+
+pub struct KeepAlive {
+	/// How long idle connection waits for the next request, also limits every read of request
+	pub timeout: Duration,
+	/// Maximum count of requests served by one connection, `1` means that keep-alive is disabled
+	pub max: usize
+}
+
+impl KeepAlive {
+	/// Zero timeout disables keep-alive, reads are still limited by default timeout
+	fn from (obj: &JsonValue) -> Self {
+		let timeout = obj["timeout"].as_u64().unwrap_or(5);
+		let max = obj["max"].as_usize().unwrap_or(100).max(1);
+
+		KeepAlive {
+			timeout: Duration::from_secs(if timeout == 0 { 5 } else { timeout }),
+			max: if timeout == 0 { 1 } else { max }
+		}
+	}
+}
+
+pub struct WebSocketConfig {
+	/// How long socket may stay silent before it's pinged, `None` if disabled
+	pub ping_interval: Option<Duration>,
+	/// How long ping waits for any reply, also limits closing handshake and writes
+	pub pong_timeout: Duration,
+	/// Socket that sends no messages for this time is closed, `None` if disabled
+	pub idle_timeout: Option<Duration>,
+	/// Maximum size of messages waiting to be sent to one socket in bytes, socket is closed if client doesn't read them
+	pub send_queue: usize,
+	pub deflate: DeflateConfig
+}
+
+impl WebSocketConfig {
+	fn from (obj: &JsonValue) -> Self {
+		WebSocketConfig {
+			ping_interval: Some(obj["pingInterval"].as_u64().unwrap_or(30)).filter(|secs| *secs != 0).map(Duration::from_secs),
+			pong_timeout: Duration::from_secs(obj["pongTimeout"].as_u64().unwrap_or(10).max(1)),
+			idle_timeout: Some(obj["idleTimeout"].as_u64().unwrap_or(0)).filter(|secs| *secs != 0).map(Duration::from_secs),
+			send_queue: obj["sendQueue"].as_usize().unwrap_or(1024 * 1024),
+			deflate: DeflateConfig::from(&obj["deflate"])
+		}
+	}
+}
+
+/// Settings of `permessage-deflate` for endpoints where it's enabled
+pub struct DeflateConfig {
+	/// Base-two logarithm of LZ77 window size, from 9 to 15
+	pub window_bits: u8,
+	/// Smaller messages are sent uncompressed
+	pub threshold: usize
+}
+
+impl DeflateConfig {
+	fn from (obj: &JsonValue) -> Self {
+		DeflateConfig {
+			window_bits: obj["window"].as_u8().unwrap_or(15).clamp(9, 15),
+			threshold: obj["threshold"].as_usize().unwrap_or(1024)
+		}
+	}
+}
+
+pub struct CookieConfig {
+	/// Key of signed cookies, they can't be used without it
+	pub secret: Option<String>
+}
+
+impl CookieConfig {
+	fn from (obj: &JsonValue) -> Self {
+		CookieConfig {
+			secret: obj["secret"].as_str().filter(|secret| !secret.is_empty()).map(str::to_string)
+		}
+	}
+}
+
+pub struct SessionConfig {
+	/// How long unused session is kept
+	pub ttl: Duration,
+	pub store: SessionStoreType,
+	/// Name of cookie with session token, `None` if only `session` header is used
+	pub cookie: Option<String>
+}
+
+pub enum SessionStoreType {
+	Memory,
+	/// Every session is stored in separate file inside directory
+	File(PathBuf)
+}
+
+impl SessionConfig {
+	fn from (obj: &JsonValue) -> Self {
+		let store = match obj["store"].as_str() {
+			Some("file") => SessionStoreType::File(PathBuf::from(obj["path"].as_str().unwrap_or("sessions"))),
+			_ => SessionStoreType::Memory
+		};
+
+		let cookie = match &obj["cookie"] {
+			JsonValue::Boolean(false) => None,
+			value => Some(value.as_str().unwrap_or("session").to_string())
+		};
+
+		SessionConfig {
+			ttl: Duration::from_secs(obj["ttl"].as_u64().unwrap_or(86400).max(1)),
+			store,
+			cookie
+		}
+	}
 }
 
 impl Config {
@@ -47,7 +204,16 @@ impl Config {
 		let host = &obj["host"];
 		let host = host.as_str().unwrap_or("0.0.0.0").to_string();
 
-		let config = Config { obj, host, port };
+		let limits = Limits::from(&obj["limits"]);
+		let keep_alive = KeepAlive::from(&obj["keepAlive"]);
+		let websocket = WebSocketConfig::from(&obj["webSocket"]);
+		let session = SessionConfig::from(&obj["session"]);
+		let cookies = CookieConfig::from(&obj["cookies"]);
+		let workers = obj["workers"].as_usize().unwrap_or(32).max(1);
+		let on_accept_error = AcceptErrorPolicy::from(&obj["onAcceptError"]);
+		let shutdown_timeout = Duration::from_secs(obj["shutdownTimeout"].as_u64().unwrap_or(10));
+
+		let config = Config { obj, host, port, limits, keep_alive, websocket, session, cookies, workers, on_accept_error, shutdown_timeout };
 		return unsafe { CONFIG.insert(config) };
 	}
 
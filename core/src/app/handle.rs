@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Mutex, atomic::{AtomicBool, AtomicUsize, Ordering}};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use signal_hook::{consts::{SIGINT, SIGTERM}, flag, SigId};
+use tungstenite::protocol::frame::coding::CloseCode;
+use crate::{utils::log::log_info, websocket::channels::Channels};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Controls server started by `spawn_server`
+pub struct ServerHandle {
+    state: Arc<ServerState>,
+    thread: JoinHandle<()>
+}
+
+impl ServerHandle {
+    pub(crate) fn spawn<F: FnOnce(Arc<ServerState>) + Send + 'static> (start: F) -> Self {
+        let state = Arc::new(ServerState::new());
+        let thread_state = state.clone();
+        let thread = thread::spawn(move || start(thread_state));
+
+        return ServerHandle { state, thread };
+    }
+
+    /// Stops accepting connections, active ones are served until `shutdownTimeout` expires
+    #[inline]
+    pub fn shutdown (&self) {
+        self.state.shutdown();
+    }
+
+    #[inline]
+    pub fn is_stopping (&self) -> bool {
+        return self.state.is_stopping();
+    }
+
+    /// Blocks until server is stopped
+    pub fn wait (self) {
+        let _ = self.thread.join();
+    }
+}
+
+/// State shared between server, its connections and handle
+pub(crate) struct ServerState {
+    stopping: Arc<AtomicBool>,
+    next_id: AtomicUsize,
+    /// Streams are kept to close connections left after shutdown timeout
+    connections: Mutex<HashMap<usize, Option<TcpStream>>>
+}
+
+impl ServerState {
+    fn new () -> Self {
+        ServerState {
+            stopping: Arc::new(AtomicBool::new(false)),
+            next_id: AtomicUsize::new(0),
+            connections: Mutex::new(HashMap::new())
+        }
+    }
+
+    pub fn shutdown (&self) {
+        self.stopping.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_stopping (&self) -> bool {
+        return self.stopping.load(Ordering::SeqCst);
+    }
+
+    /// SIGTERM and SIGINT start graceful shutdown, second signal terminates process immediately
+    pub fn watch_signals (&self) -> Vec<SigId> {
+        let mut ids = Vec::new();
+        for signal in [SIGTERM, SIGINT] {
+            if let Ok(id) = flag::register_conditional_shutdown(signal, 1, self.stopping.clone()) { ids.push(id); }
+            if let Ok(id) = flag::register(signal, self.stopping.clone()) { ids.push(id); }
+        }
+
+        return ids;
+    }
+
+    pub fn unwatch_signals (&self, ids: Vec<SigId>) {
+        for id in ids {
+            signal_hook::low_level::unregister(id);
+        }
+    }
+
+    /// Registers connection, it's counted as active until returned guard is dropped
+    pub fn track (self: &Arc<Self>, stream: Option<TcpStream>) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().insert(id, stream);
+        return ConnectionGuard { state: self.clone(), id };
+    }
+
+    pub fn active_count (&self) -> usize {
+        return self.connections.lock().unwrap().len();
+    }
+
+    /// Waits for active connections, remaining ones are closed after `timeout`.
+    /// WebSockets are asked to close with `1001 Going Away`, so clients can reconnect to another server.
+    pub fn drain (&self, timeout: Duration, channels: &Channels) {
+        let deadline = Instant::now() + timeout;
+        let count = self.active_count();
+        if count != 0 {
+            log_info(&format!("Waiting for {} active connection(s)", count));
+        }
+
+        channels.close_all(u16::from(CloseCode::Away), "Server is shutting down");
+
+        while self.active_count() != 0 && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        // Unblocks idle keep-alive connections and WebSockets
+        for stream in self.connections.lock().unwrap().values().flatten() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+pub(crate) struct ConnectionGuard {
+    state: Arc<ServerState>,
+    id: usize
+}
+
+impl ConnectionGuard {
+    /// Sets stream that is closed if connection outlives shutdown timeout
+    #[cfg(feature = "async")]
+    pub fn set_stream (&self, stream: TcpStream) {
+        self.state.connections.lock().unwrap().insert(self.id, Some(stream));
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop (&mut self) {
+        self.state.connections.lock().unwrap().remove(&self.id);
+    }
+}
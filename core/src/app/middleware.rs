@@ -0,0 +1,100 @@
+use crate::{context::http::HttpContext, http::entity::Response};
+use super::router::Route;
+
+pub(crate) type MiddlewareCallerType = dyn Fn(&mut HttpContext) -> Flow + Sync + Send + 'static;
+type AfterCallerType = dyn FnOnce(&mut Response) + Send + 'static;
+
+/// Result of middleware, it decides whether request goes further
+pub enum Flow {
+    /// Passes request to the next middleware
+    Next,
+    /// Same as `Next`, but function is called with response on the way back
+    After(Box<AfterCallerType>),
+    /// Stops chain, response is sent without calling route action
+    Respond(Response)
+}
+
+impl Flow {
+    #[inline]
+    pub fn after<Caller: FnOnce(&mut Response) + Send + 'static> (caller: Caller) -> Self {
+        return Flow::After(Box::new(caller));
+    }
+}
+
+struct Middleware {
+    /// `None` means that middleware is called for every request
+    prefix: Option<String>,
+    handler: Box<MiddlewareCallerType>
+}
+
+/// Middlewares are called in order they were added before route action of HTTP request,
+/// functions returned with `Flow::After` are called in reverse order
+pub struct Middlewares {
+    list: Vec<Middleware>
+}
+
+impl Middlewares {
+    pub fn empty () -> Self {
+        Middlewares { list: Vec::new() }
+    }
+
+    /// Adds middleware for all requests, including ones that wasn't routed
+    pub fn add<Caller: Fn(&mut HttpContext) -> Flow + Sync + Send + 'static> (&mut self, handler: Caller) {
+        self.list.push(Middleware { prefix: None, handler: Box::new(handler) });
+    }
+
+    /// Adds middleware for paths starting with `prefix`, e.g. `/api` matches `/api` and `/api/users`, but not `/apix`
+    pub fn scope<Caller: Fn(&mut HttpContext) -> Flow + Sync + Send + 'static> (&mut self, prefix: &str, handler: Caller) {
+        let prefix = prefix.trim_end_matches('/').to_string();
+        self.list.push(Middleware { prefix: Some(prefix), handler: Box::new(handler) });
+    }
+
+    #[inline]
+    pub fn len (&self) -> usize {
+        return self.list.len();
+    }
+
+    #[inline]
+    pub fn is_empty (&self) -> bool {
+        return self.list.is_empty();
+    }
+
+    /// Calls middlewares matching request path and then middlewares of route groups,
+    /// returns response if chain was stopped. Requests rejected by router have no `route`.
+    pub(crate) fn run (&self, ctx: &mut HttpContext, route: Option<&Route>) -> (Pending, Option<Response>) {
+        let path = ctx.req.path.clone();
+        let global = self.list.iter()
+            .filter(|middleware| !matches!(&middleware.prefix, Some(prefix) if !is_under_prefix(&path, prefix)))
+            .map(|middleware| &*middleware.handler);
+        let grouped = route.into_iter().flat_map(|route| &route.middlewares).map(|handler| &**handler);
+
+        let mut pending = Pending(Vec::new());
+        for handler in global.chain(grouped) {
+            match handler(ctx) {
+                Flow::Next => {},
+                Flow::After(after) => pending.0.push(after),
+                Flow::Respond(res) => return (pending, Some(res))
+            }
+        }
+
+        return (pending, None);
+    }
+}
+
+/// Post-processors of middlewares that were called for request
+pub(crate) struct Pending(Vec<Box<AfterCallerType>>);
+
+impl Pending {
+    pub fn finish (self, res: &mut Response) {
+        for after in self.0.into_iter().rev() {
+            after(res);
+        }
+    }
+}
+
+fn is_under_prefix (path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false
+    }
+}
@@ -1,20 +1,37 @@
-use crate::websocket::WebSocketEndpoints;
-use self::router::Router;
+use std::sync::Arc;
+use crate::{http::cors::cors_middleware, session::Sessions, websocket::{WebSocketEndpoints, channels::Channels}};
+use self::{middleware::Middlewares, router::Router};
 
 pub mod config;
 pub mod server;
+pub mod handle;
+#[cfg(feature = "async")]
+pub mod async_server;
 pub mod router;
+pub mod middleware;
 
 pub struct App {
 	pub ws_endpoints: WebSocketEndpoints,
-	pub router: Router
+	/// Can be cloned before spawning server to broadcast from HTTP handlers
+	pub channels: Arc<Channels>,
+	pub router: Router,
+	/// Called around route actions, CORS middleware is added by default
+	pub middlewares: Middlewares,
+	/// Session layer, disabled by default
+	pub sessions: Option<Sessions>
 }
 
 impl App {
 	pub fn new () -> Self {
+		let mut middlewares = Middlewares::empty();
+		middlewares.add(cors_middleware);
+
 		App {
 			ws_endpoints: WebSocketEndpoints::empty(),
-			router: Router::empty()
+			channels: Arc::new(Channels::new()),
+			router: Router::empty(),
+			middlewares,
+			sessions: None
 		}
 	}
 }
@@ -1,44 +1,248 @@
-use std::collections::HashMap;
-use crate::{context::http::HttpContext, http::entity::Response};
+use std::{collections::HashMap, ops::{Deref, DerefMut}, sync::Arc};
+#[cfg(feature = "async")]
+use std::{future::Future, pin::Pin};
+use crate::{context::http::HttpContext, http::{cors::CorsConfig, entity::{HttpMethod, Response}}};
+use super::middleware::{Flow, MiddlewareCallerType};
 
-type ActionCallerType = dyn FnMut(HttpContext) -> Response + Sync + Send + 'static;
+type ActionCallerType = dyn Fn(HttpContext) -> Response + Sync + Send + 'static;
+
+#[cfg(feature = "async")]
+pub type ActionFuture = Pin<Box<dyn Future<Output = Response> + Send + 'static>>;
+#[cfg(feature = "async")]
+type AsyncActionCallerType = dyn Fn(HttpContext) -> ActionFuture + Sync + Send + 'static;
+
+pub enum RouteAction {
+    Sync(Arc<ActionCallerType>),
+    #[cfg(feature = "async")]
+    Async(Arc<AsyncActionCallerType>)
+}
 
 pub struct Route {
+    /// `None` means that route accepts any method
+    pub method: Option<HttpMethod>,
     pub matcher: PathMatcher,
-    pub call: Box<ActionCallerType>
+    pub action: RouteAction,
+    /// Middlewares of groups route belongs to, outer groups go first
+    pub(crate) middlewares: Vec<Arc<MiddlewareCallerType>>,
+    /// CORS policy of the nearest group that overrides it
+    pub(crate) cors: Option<Arc<CorsConfig>>
 }
 
 impl Route {
-    pub fn new (pattern: String, action: Box<ActionCallerType>) -> Self {
+    pub fn new (method: Option<HttpMethod>, pattern: String, action: RouteAction) -> Self {
         return Route {
+            method,
             matcher: PathMatcher::from_pattern(pattern),
-            call: action
+            action,
+            middlewares: Vec::new(),
+            cors: None
         }
     }
+
+    /// Calls action blocking current thread until response is ready
+    pub fn call (&self, ctx: HttpContext) -> Response {
+        match &self.action {
+            RouteAction::Sync(action) => action(ctx),
+            #[cfg(feature = "async")]
+            RouteAction::Async(action) => block_on(action(ctx))
+        }
+    }
+
+    /// Calls action from async engine, blocking actions don't stall other tasks
+    #[cfg(feature = "async")]
+    pub async fn call_async (&self, ctx: HttpContext) -> Response {
+        match &self.action {
+            RouteAction::Sync(action) => tokio::task::block_in_place(|| action(ctx)),
+            RouteAction::Async(action) => action(ctx).await
+        }
+    }
+}
+
+/// Runs async action on blocking worker thread
+#[cfg(feature = "async")]
+fn block_on (future: ActionFuture) -> Response {
+    thread_local! {
+        static RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("Can't create runtime for async actions");
+    }
+
+    return RUNTIME.with(|runtime| runtime.block_on(future));
+}
+
+pub enum RouteMatch<'a> {
+    Found(&'a Route, HashMap<String, String>),
+    /* allowed methods */
+    MethodNotAllowed(Vec<HttpMethod>),
+    NotFound
 }
 
 pub struct Router {
-    pub routes: Vec<Route>
+    pub routes: Vec<Route>,
+    /// Prefix of group which routes are being registered
+    prefix: String
 }
 
 impl Router {
     pub fn empty () -> Self {
-        Router { routes: Vec::new() }
+        Router { routes: Vec::new(), prefix: String::new() }
+    }
+
+    /// Registers route for any method
+    #[inline]
+    pub fn register<Caller: Fn(HttpContext) -> Response + Sync + Send + 'static> (&mut self, pattern: String, action: Caller) {
+        self.push(None, pattern, RouteAction::Sync(Arc::new(action)));
+    }
+
+    #[inline]
+    pub fn on<Caller: Fn(HttpContext) -> Response + Sync + Send + 'static> (&mut self, method: HttpMethod, pattern: String, action: Caller) {
+        self.push(Some(method), pattern, RouteAction::Sync(Arc::new(action)));
+    }
+
+    /// Registers async route for any method
+    #[cfg(feature = "async")]
+    pub fn register_async<Caller, Fut> (&mut self, pattern: String, action: Caller)
+    where Caller: Fn(HttpContext) -> Fut + Sync + Send + 'static, Fut: Future<Output = Response> + Send + 'static {
+        self.push(None, pattern, RouteAction::Async(Arc::new(move |ctx| Box::pin(action(ctx)))));
+    }
+
+    #[cfg(feature = "async")]
+    pub fn on_async<Caller, Fut> (&mut self, method: HttpMethod, pattern: String, action: Caller)
+    where Caller: Fn(HttpContext) -> Fut + Sync + Send + 'static, Fut: Future<Output = Response> + Send + 'static {
+        self.push(Some(method), pattern, RouteAction::Async(Arc::new(move |ctx| Box::pin(action(ctx)))));
+    }
+
+    fn push (&mut self, method: Option<HttpMethod>, pattern: String, action: RouteAction) {
+        self.routes.push(Route::new(method, format!("{}{}", self.prefix, pattern), action));
+    }
+
+    /// Registers routes with common path prefix, patterns inside group are relative to it.
+    /// Groups can be nested, middlewares and CORS policy of group are applied to all its routes.
+    pub fn group<Builder: FnOnce(&mut RouteGroup)> (&mut self, prefix: &str, builder: Builder) {
+        let start = self.routes.len();
+        let parent_len = self.prefix.len();
+        self.prefix.push_str(prefix.trim_end_matches('/'));
+
+        let mut group = RouteGroup { router: self, middlewares: Vec::new(), cors: None };
+        builder(&mut group);
+        let RouteGroup { middlewares, cors, .. } = group;
+
+        self.prefix.truncate(parent_len);
+        for route in &mut self.routes[start..] {
+            route.middlewares.splice(0..0, middlewares.iter().cloned());
+            if route.cors.is_none() { route.cors = cors.clone(); }
+        }
+    }
+
+    /// First route matching path with any method, it defines middlewares and CORS policy of request
+    pub fn match_path (&self, path: &str) -> Option<&Route> {
+        return self.routes.iter().find(|route| route.matcher.exec(path).is_some());
+    }
+
+    /// Registers route for `GET` method, `HEAD` requests are served by it too
+    #[inline]
+    pub fn get<Caller: Fn(HttpContext) -> Response + Sync + Send + 'static> (&mut self, pattern: String, action: Caller) {
+        self.on(HttpMethod::GET, pattern, action);
+    }
+
+    #[inline]
+    pub fn head<Caller: Fn(HttpContext) -> Response + Sync + Send + 'static> (&mut self, pattern: String, action: Caller) {
+        self.on(HttpMethod::HEAD, pattern, action);
+    }
+
+    #[inline]
+    pub fn post<Caller: Fn(HttpContext) -> Response + Sync + Send + 'static> (&mut self, pattern: String, action: Caller) {
+        self.on(HttpMethod::POST, pattern, action);
+    }
+
+    #[inline]
+    pub fn put<Caller: Fn(HttpContext) -> Response + Sync + Send + 'static> (&mut self, pattern: String, action: Caller) {
+        self.on(HttpMethod::PUT, pattern, action);
+    }
+
+    #[inline]
+    pub fn patch<Caller: Fn(HttpContext) -> Response + Sync + Send + 'static> (&mut self, pattern: String, action: Caller) {
+        self.on(HttpMethod::PATCH, pattern, action);
     }
 
     #[inline]
-    pub fn register<Caller: FnMut(HttpContext) -> Response + Sync + Send + 'static> (&mut self, pattern: String, action: Caller) {
-        self.routes.push(Route::new(pattern, Box::new(action)));
+    pub fn delete<Caller: Fn(HttpContext) -> Response + Sync + Send + 'static> (&mut self, pattern: String, action: Caller) {
+        self.on(HttpMethod::DELETE, pattern, action);
     }
 
-    pub fn match_path<'a> (&'a mut self, path: &String) -> Option<(&'a mut Route, HashMap<String, String>)> {
-        for route in &mut self.routes {
-            if let Some(params) = route.matcher.exec(&path) {
-                return Some((route, params));
+    pub fn match_route<'a> (&'a self, method: HttpMethod, path: &str) -> RouteMatch<'a> {
+        let mut found = None;
+        let mut head_fallback = None;
+        let mut allowed = Vec::new();
+
+        for (index, route) in self.routes.iter().enumerate() {
+            if let Some(params) = route.matcher.exec(path) {
+                match route.method {
+                    None => {
+                        found = Some((index, params));
+                        break;
+                    }
+                    Some(route_method) if route_method == method => {
+                        found = Some((index, params));
+                        break;
+                    }
+                    Some(route_method) => {
+                        if route_method == HttpMethod::GET && method == HttpMethod::HEAD && head_fallback.is_none() {
+                            head_fallback = Some((index, params));
+                        }
+
+                        if !allowed.contains(&route_method) { allowed.push(route_method); }
+                    }
+                }
             }
         }
 
-        return None;
+        if let Some((index, params)) = found.or(head_fallback) {
+            return RouteMatch::Found(&self.routes[index], params);
+        } else if allowed.is_empty() {
+            return RouteMatch::NotFound;
+        }
+
+        if allowed.contains(&HttpMethod::GET) && !allowed.contains(&HttpMethod::HEAD) {
+            allowed.push(HttpMethod::HEAD);
+        }
+
+        allowed.push(HttpMethod::OPTIONS);
+        return RouteMatch::MethodNotAllowed(allowed);
+    }
+}
+
+/// Router with path prefix of group, routes are registered with the same methods as in `Router`
+pub struct RouteGroup<'a> {
+    router: &'a mut Router,
+    middlewares: Vec<Arc<MiddlewareCallerType>>,
+    cors: Option<Arc<CorsConfig>>
+}
+
+impl RouteGroup<'_> {
+    /// Adds middleware that is called after global ones for routes of group only
+    pub fn middleware<Caller: Fn(&mut HttpContext) -> Flow + Sync + Send + 'static> (&mut self, handler: Caller) {
+        self.middlewares.push(Arc::new(handler));
+    }
+
+    /// Overrides CORS policy from config for routes of group
+    pub fn cors (&mut self, policy: CorsConfig) {
+        self.cors = Some(Arc::new(policy));
+    }
+}
+
+impl Deref for RouteGroup<'_> {
+    type Target = Router;
+
+    fn deref (&self) -> &Router {
+        return self.router;
+    }
+}
+
+impl DerefMut for RouteGroup<'_> {
+    fn deref_mut (&mut self) -> &mut Router {
+        return self.router;
     }
 }
 
@@ -64,6 +268,11 @@ impl PathMatcher {
                 } else {
                     tmp.push(ch);
                 }
+            } else if is_var_end {
+                // Character right after variable terminates its value
+                sequence.push(PathPart::Variable(tmp, ch));
+                tmp = String::new();
+                is_var_end = false;
             } else if ch == '{' {
                 if tmp.len() != 0 {
                     sequence.push(PathPart::String(tmp));
@@ -71,9 +280,6 @@ impl PathMatcher {
                 }
 
                 is_var = true;
-            } else if is_var_end {
-                sequence.push(PathPart::Variable(tmp, ch));
-                tmp = String::new();
             } else {
                 tmp.push(ch);
             }
@@ -88,45 +294,71 @@ impl PathMatcher {
         return PathMatcher(sequence);
     }
 
-    pub fn exec (&self, path: &String) -> Option<HashMap<String, String>> {
-        let mut offset = 0usize;
-        let mut path_iter = path.chars();
+    pub fn exec (&self, path: &str) -> Option<HashMap<String, String>> {
+        let mut rest = path;
         let mut params: HashMap<String, String> = HashMap::new();
 
         for part in &self.0 {
             match part {
                 PathPart::String(value) => {
-                    let mut part_iter = value.chars();
-                    loop {
-                        if let Some(ch) = part_iter.next() {
-                            let next_ch = path_iter.next();
-                            if next_ch.is_none() || ch != next_ch.unwrap() {
-                                return None;
-                            }
-                        } else {
-                            if let None = path_iter.next() { break; }
-                            else { return None; }
-                        }
-                    }
-
-                    offset += value.len();
+                    rest = rest.strip_prefix(value.as_str())?;
                 }
                 PathPart::Variable(name, stop_char) => {
-                    let mut i = 0usize;
-                    loop {
-                        let next = path_iter.next();
-                        if next.is_none() || next.unwrap() == *stop_char { break }
-                        i += 1;
-                    }
+                    let (value, next) = if *stop_char == '\0' {
+                        (rest, "")
+                    } else {
+                        let index = rest.find(*stop_char)?;
+                        (&rest[..index], &rest[(index + stop_char.len_utf8())..])
+                    };
+
+                    // Variable can't be empty or span multiple path segments
+                    if value.is_empty() || value.contains('/') { return None; }
 
-                    if offset >= path.len() { return None; }
-                    let value = &path[(offset)..(offset + i)];
-                    offset += i + 1;
-					params.insert(name.to_string(), value.to_string());
+                    params.insert(name.to_string(), value.to_string());
+                    rest = next;
                 }
             }
         }
 
+        return if rest.is_empty() { Some(params) } else { None };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PathMatcher;
+
+    fn exec (pattern: &str, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params: Vec<_> = PathMatcher::from_pattern(pattern.to_string()).exec(path)?.into_iter().collect();
+        params.sort();
         return Some(params);
     }
+
+    fn params (pairs: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        return Some(pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect());
+    }
+
+    #[test]
+    fn variables_split_by_separator () {
+        assert_eq!(exec("/{a}-{b}", "/x-y"), params(&[("a", "x"), ("b", "y")]));
+        assert_eq!(exec("/{a}-{b}", "/long-value-rest"), params(&[("a", "long"), ("b", "value-rest")]));
+        assert_eq!(exec("/{a}-{b}", "/xy"), None);
+    }
+
+    #[test]
+    fn trailing_segments_are_not_matched () {
+        assert_eq!(exec("/item/{id}", "/item/1"), params(&[("id", "1")]));
+        assert_eq!(exec("/item/{id}", "/item/1/extra"), None);
+        assert_eq!(exec("/item/{id}/edit", "/item/1/edit/more"), None);
+        assert_eq!(exec("/ip", "/ip/"), None);
+        assert_eq!(exec("/ip", "/ip"), params(&[]));
+    }
+
+    #[test]
+    fn empty_variables_are_rejected () {
+        assert_eq!(exec("/item/{id}", "/item/"), None);
+        assert_eq!(exec("/item/{id}/edit", "/item//edit"), None);
+        assert_eq!(exec("/{a}-{b}", "/-y"), None);
+        assert_eq!(exec("/{a}-{b}", "/x-"), None);
+    }
 }
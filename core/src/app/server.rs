@@ -1,97 +1,233 @@
+use std::collections::HashMap;
 use std::io::Error;
-use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::sync::Mutex;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use threadpool::ThreadPool;
 
-use super::config::Config;
-use crate::http::cors::Cors;
+use super::config::{AcceptErrorPolicy, Config};
+use super::handle::ServerState;
+use crate::http::cors::CorsConfig;
 use crate::utils::log::*;
 use super::App;
+use super::router::{Route, RouteMatch};
 use crate::context::http::HttpContext;
 use crate::context::ws::SocketContext;
 use crate::http::{entity::*, codes::HttpCode};
 use crate::http1::{Http1Engine, Http1Connection};
 use crate::websocket::{websocket_handshake, HandshakeResult, maintain_websocket};
 
-pub fn start_server (app_mutex: &'static Mutex<App>) {
-    let app = app_mutex.lock().unwrap();
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
 
+pub(crate) fn start_server (app: Arc<App>, state: Arc<ServerState>) {
     let config = Config::get();
+    // Lazy policy initialization isn't synchronized, so it's done before spawning workers
+    CorsConfig::get();
+
     let bind_address = format!("{}:{}", config.host, config.port);
 
-	match TcpListener::bind(&bind_address) {
+    match TcpListener::bind(&bind_address) {
         Ok(listener) => {
-            drop(app);
+            let pool = ThreadPool::new(config.workers);
+            let signals = state.watch_signals();
+            spawn_accept_waker(&listener, state.clone());
+            log_success(&format!("Listening on {}", bind_address));
+
+            loop {
+                match listener.accept() {
+                    Ok(socket) => {
+                        if state.is_stopping() { break; }
+
+                        let guard = state.track(socket.0.try_clone().ok());
+                        let app = app.clone();
+                        let state = state.clone();
+                        pool.execute(move || {
+                            proceed_connection::<Http1Engine, Http1Connection>(&app, &state, socket);
+                            drop(guard);
+                        });
+                    }
+                    Err(error) => {
+                        if state.is_stopping() { break; }
 
-            let pool = ThreadPool::new(32);
-			log_success(&format!("Listening on {}", bind_address));
+                        log_error_lines("Accept error", error.to_string());
+                        match config.on_accept_error {
+                            AcceptErrorPolicy::Retry => thread::sleep(ACCEPT_RETRY_DELAY),
+                            AcceptErrorPolicy::Shutdown => {
+                                state.shutdown();
+                                break;
+                            }
+                        }
+                    }
+                }
+            }
 
-			loop {
-				let socket = listener.accept().unwrap();
-				pool.execute(move || proceed_connection::<Http1Engine, Http1Connection>(app_mutex, socket));
-			}
-		}
-		Err(error) => {
+            drop(listener);
+            log_info("Shutting down");
+            state.drain(config.shutdown_timeout, &app.channels);
+            state.unwatch_signals(signals);
+        }
+        Err(error) => {
             log_error_lines("Listen error", error.to_string());
-		}
-	}
+        }
+    }
+}
+
+/// Blocking `accept` can't be interrupted, so listener is woken up by connection to itself
+fn spawn_accept_waker (listener: &TcpListener, state: Arc<ServerState>) {
+    let address = match listener.local_addr() {
+        Ok(mut address) => {
+            if address.ip().is_unspecified() {
+                address.set_ip(if address.is_ipv4() { Ipv4Addr::LOCALHOST.into() } else { Ipv6Addr::LOCALHOST.into() });
+            }
+
+            address
+        }
+        Err(_) => return
+    };
+
+    thread::spawn(move || {
+        while !state.is_stopping() {
+            thread::sleep(ACCEPT_RETRY_DELAY);
+        }
+
+        let _ = TcpStream::connect_timeout(&address, ACCEPT_RETRY_DELAY);
+    });
 }
 
 fn proceed_connection<Http: HttpEngine<Connection> + Send, Connection: HttpConnection>
-(app_arc: &Mutex<App>, socket: (TcpStream, SocketAddr)) {
+(app: &App, state: &ServerState, socket: (TcpStream, SocketAddr)) {
     let mut connection = Http::handle_connection(socket);
+    let keep_alive = &Config::get().keep_alive;
+    let mut requests_count = 0usize;
+
+    if connection.set_timeout(Some(keep_alive.timeout)).is_err() {
+        let _ = connection.disconnect();
+        return;
+    }
 
-    match connection.parse() {
-        ParsingResult::Complete(req) => {
-            if is_connection_upgrade(&req) {
-                if is_websocket_upgrade(&req) {
-                    proceed_websocket::<Connection>(app_arc, connection, req);
-                } else {
-                    let _ = connection.respond(Response::from_status(HttpCode::BadRequest));
+    loop {
+        match connection.parse() {
+            ParsingResult::Complete(req) => {
+                requests_count += 1;
+
+                if is_connection_upgrade(&req) {
+                    if is_websocket_upgrade(&req) && connection.set_timeout(None).is_ok() {
+                        proceed_websocket::<Connection>(app, connection, req);
+                        return;
+                    } else {
+                        let _ = connection.respond(Response::from_status(HttpCode::BadRequest));
+                        break;
+                    }
+                }
+
+                let is_keep_alive = requests_count < keep_alive.max && !state.is_stopping() && connection.is_keep_alive(&req);
+                if !matches!(proceed_http::<Connection>(app, &mut connection, req, is_keep_alive), Ok(true)) {
+                    break;
                 }
-            } else {
-                let _ = proceed_http::<Connection>(app_arc, connection, req);
             }
+            ParsingResult::Partial => break,
+            ParsingResult::Error(res_code) => {
+                let mut res = Response::from_status(res_code);
+                res.headers.set("connection".to_string(), "close".to_string());
+                let _ = connection.respond(res);
+                break;
+            }
+            ParsingResult::Invalid => break
         }
-        ParsingResult::Partial => {}
-        ParsingResult::Error(res_code) => {
-            let _ = connection.respond(Response::from_status(res_code));
-            let _ = connection.disconnect();
+    }
+
+    let _ = connection.disconnect();
+}
+
+/// Returns `false` if connection can't be reused
+fn proceed_http<Connection: HttpConnection> (app: &App, connection: &mut Connection, req: Request, is_keep_alive: bool) -> Result<bool, Error> {
+    let is_head = req.method == HttpMethod::HEAD;
+    let (route, params) = route_request(app, &req);
+    let cors = route_cors(app, &req, &route);
+    let mut ctx = HttpContext::from(connection, req, params);
+    if let Some(cors) = cors { ctx.extensions.insert(cors); }
+    let cookies = ctx.cookies.outgoing_handle();
+    let session = app.sessions.as_ref().map(|sessions| sessions.start(&mut ctx));
+
+    let (pending, stopped) = app.middlewares.run(&mut ctx, route.as_ref().ok().copied());
+    let mut res = match (stopped, route) {
+        (Some(res), _) | (None, Err(res)) => res,
+        (None, Ok(route)) => route.call(ctx)
+    };
+
+    pending.finish(&mut res);
+    cookies.apply(&mut res);
+    if let (Some(sessions), Some(session)) = (&app.sessions, session) {
+        sessions.finish(session, &mut res);
+    }
+
+    let mut res = finish_response(res, is_head);
+    if is_dropped(&res) { return Ok(false); }
+
+    set_keep_alive(&mut res, is_keep_alive);
+    connection.respond(res)?;
+    return Ok(is_keep_alive);
+}
+
+/// Finds route for request, response for requests that can't be routed is still passed through middlewares
+pub(crate) fn route_request<'a> (app: &'a App, req: &Request) -> (Result<&'a Route, Response>, HashMap<String, String>) {
+    match app.router.match_route(req.method, &req.path) {
+        RouteMatch::Found(route, params) => return (Ok(route), params),
+        RouteMatch::MethodNotAllowed(allowed) => {
+            let mut res = Response::from_code(HttpCode::MethodNotAllowed, "Method not allowed");
+            let allowed: Vec<&str> = allowed.iter().map(HttpMethod::as_str).collect();
+            res.headers.set("allow".to_string(), allowed.join(", "));
+            return (Err(res), HashMap::new());
         }
-        ParsingResult::Invalid => {
-            let _ = connection.disconnect();
+        RouteMatch::NotFound => {
+            return (Err(Response::from_code(HttpCode::NotFound, "API endpoint not found")), HashMap::new());
         }
     }
 }
 
-fn proceed_http<Connection: HttpConnection> (app_mutex: &Mutex<App>, mut connection: Connection, req: Request) -> Result<(), Error> {
-    let mut res;
-    let mut app = app_mutex.lock().unwrap();
-
-    let cors = Cors::new(&req);
-    if let HttpMethod::OPTIONS = req.method {
-        res = Response::from_status(HttpCode::OK);
-        cors.apply_preflight(&mut res);
-    } else if let Some((endpoint, params)) = app.router.match_path(&req.path) {
-        let ctx = HttpContext::from(&connection, req, params);
-        res = (endpoint.call)(ctx);
-        cors.apply_normal(&mut res);
+/// CORS policy of route group, it's looked up by path only, so preflight and rejected requests get it too
+pub(crate) fn route_cors (app: &App, req: &Request, route: &Result<&Route, Response>) -> Option<Arc<CorsConfig>> {
+    let route = match route {
+        Ok(route) => Some(*route),
+        Err(_) => app.router.match_path(&req.path)
+    };
+
+    return route.and_then(|route| route.cors.clone());
+}
+
+/// Prepares response of route action or middleware for sending
+pub(crate) fn finish_response (mut res: Response, is_head: bool) -> Response {
+    if is_head { res = res.into_head(); }
+    return res;
+}
+
+/// Dropped response isn't sent, connection is closed instead, so client doesn't wait for it
+#[inline]
+pub(crate) fn is_dropped (res: &Response) -> bool {
+    return matches!(res.payload, ResponseType::Drop);
+}
+
+pub(crate) fn set_keep_alive (res: &mut Response, is_keep_alive: bool) {
+    if is_keep_alive {
+        res.headers.set("connection".to_string(), "keep-alive".to_string());
+        let timeout = Config::get().keep_alive.timeout;
+        res.headers.set("keep-alive".to_string(), format!("timeout={}", timeout.as_secs()));
     } else {
-        res = Response::from_code(HttpCode::NotFound, "API endpoint not found");
-        cors.apply_normal(&mut res);
+        res.headers.set("connection".to_string(), "close".to_string());
     }
-
-    connection.respond(res)?;
-    return connection.disconnect();
 }
 
-fn proceed_websocket<Connection: HttpConnection> (app_mutex: &Mutex<App>, mut connection: Connection, req: Request) {
-    match websocket_handshake(app_mutex, &req) {
-        HandshakeResult::Ok(endpoint_index, res) => {
+fn proceed_websocket<Connection: HttpConnection> (app: &App, mut connection: Connection, req: Request) {
+    match websocket_handshake(app, &req) {
+        HandshakeResult::Ok(handshake, res) => {
             // todo: handle all `let _ = ...`
             let _ = connection.respond(res);
-            let ctx = SocketContext::from::<Connection>(connection, req);
-            let _ = maintain_websocket(app_mutex, ctx, endpoint_index);
+            let endpoint_index = handshake.endpoint;
+            let endpoint = &app.ws_endpoints.at(endpoint_index).unwrap().path;
+            if let Ok(ctx) = SocketContext::from::<Connection>(connection, req, handshake, &app.channels, endpoint) {
+                let _ = maintain_websocket(app, ctx, endpoint_index);
+            }
         }
         HandshakeResult::Err(res) => {
             let _ = connection.respond(res);
@@ -100,10 +236,10 @@ fn proceed_websocket<Connection: HttpConnection> (app_mutex: &Mutex<App>, mut co
     }
 }
 
-fn is_connection_upgrade (req: &Request) -> bool {
-    matches!(req.headers.get("connection"), Some(value) if value == "Upgrade")
+pub(crate) fn is_connection_upgrade (req: &Request) -> bool {
+    req.headers.has_token("connection", "upgrade")
 }
 
-fn is_websocket_upgrade (req: &Request) -> bool {
-    matches!(req.headers.get("upgrade"), Some(value) if value == "websocket")
+pub(crate) fn is_websocket_upgrade (req: &Request) -> bool {
+    req.headers.has_token("upgrade", "websocket")
 }
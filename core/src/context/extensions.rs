@@ -0,0 +1,55 @@
+use std::{any::{Any, TypeId}, collections::HashMap, fmt};
+
+/// Typed storage of user values, one value per type.
+/// Lives as long as context: single request for `HttpContext`, whole connection for `SocketContext`.
+#[derive(Default)]
+pub struct Extensions(HashMap<TypeId, Box<dyn Any + Send + Sync>>);
+
+impl Extensions {
+	pub fn new () -> Self {
+		Extensions(HashMap::new())
+	}
+
+	/// Stores value, previous value of the same type is returned
+	pub fn insert<T: Any + Send + Sync> (&mut self, value: T) -> Option<T> {
+		return self.0.insert(TypeId::of::<T>(), Box::new(value)).and_then(|old| old.downcast().ok().map(|old| *old));
+	}
+
+	pub fn get<T: Any + Send + Sync> (&self) -> Option<&T> {
+		return self.0.get(&TypeId::of::<T>())?.downcast_ref();
+	}
+
+	pub fn get_mut<T: Any + Send + Sync> (&mut self) -> Option<&mut T> {
+		return self.0.get_mut(&TypeId::of::<T>())?.downcast_mut();
+	}
+
+	/// Returns stored value, default one is inserted first if there is no value
+	pub fn get_or_default<T: Any + Send + Sync + Default> (&mut self) -> &mut T {
+		return self.0.entry(TypeId::of::<T>()).or_insert_with(|| Box::<T>::default()).downcast_mut().unwrap();
+	}
+
+	pub fn remove<T: Any + Send + Sync> (&mut self) -> Option<T> {
+		return self.0.remove(&TypeId::of::<T>())?.downcast().ok().map(|value| *value);
+	}
+
+	#[inline]
+	pub fn contains<T: Any + Send + Sync> (&self) -> bool {
+		return self.0.contains_key(&TypeId::of::<T>());
+	}
+
+	#[inline]
+	pub fn len (&self) -> usize {
+		return self.0.len();
+	}
+
+	#[inline]
+	pub fn is_empty (&self) -> bool {
+		return self.0.is_empty();
+	}
+}
+
+impl fmt::Debug for Extensions {
+	fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "Extensions({})", self.0.len())
+	}
+}
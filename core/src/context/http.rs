@@ -1,24 +1,66 @@
-use std::{collections::HashMap, net::IpAddr};
-use crate::http::{entity::{HttpHeaders, Request, Response, ResponseType, HttpConnection}, codes::HttpCode};
+use std::{collections::HashMap, io::{Error, Write}, net::IpAddr, str};
+use json::JsonValue;
+use super::extensions::Extensions;
+use crate::http::cookie::CookieJar;
+use crate::session::{Session, SessionData, SessionRef};
+use crate::http::{entity::{HttpHeaders, Request, Response, ResponseType, HttpConnection}, codes::HttpCode, multipart::Multipart, urlencoded::UrlEncoded};
+
+const FORM_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// Request body decoded according to its `content-type`
+#[derive(Debug)]
+pub enum RequestData<'a> {
+	Empty,
+	Raw(&'a [u8]),
+	Text(&'a str),
+	Json(JsonValue),
+	Form(UrlEncoded),
+	Multipart(&'a Multipart)
+}
 
 #[derive(Debug)]
 pub struct HttpContext {
 	pub req: Request,
 	pub params: HashMap<String, String>,
+	pub query: UrlEncoded,
 	pub address: IpAddr,
-	pub res_headers: HttpHeaders
+	pub res_headers: HttpHeaders,
+	/// Values attached to request, for sockets they live as long as connection
+	pub extensions: Extensions,
+	/// Request cookies, cookies set here are added to any response of request
+	pub cookies: CookieJar
 }
 
 impl HttpContext {
-	pub fn from<Connection: HttpConnection> (connection: &Connection, req: Request, params: HashMap<String, String>) -> Self {
+	pub fn new (address: IpAddr, req: Request, params: HashMap<String, String>) -> Self {
 		HttpContext {
+			query: UrlEncoded::parse(&req.query),
+			cookies: CookieJar::from_request(&req),
 			req,
 			params,
-			address: connection.get_address(),
-			res_headers: HttpHeaders::empty()
+			address,
+			res_headers: HttpHeaders::empty(),
+			extensions: Extensions::new()
 		}
 	}
 
+	#[inline]
+	pub fn from<Connection: HttpConnection> (connection: &Connection, req: Request, params: HashMap<String, String>) -> Self {
+		return HttpContext::new(connection.get_address(), req, params);
+	}
+
+	/// Session loaded for request, `None` if sessions are disabled or used with other type.
+	/// Returned reference locks session, so it's `None` while another reference is alive.
+	pub fn session<T: SessionData> (&self) -> Option<SessionRef<'_, T>> {
+		return self.extensions.get::<Session>()?.get::<T>();
+	}
+
+	/// Session handle, it can destroy or renew session
+	#[inline]
+	pub fn session_handle (&self) -> Option<&Session> {
+		return self.extensions.get::<Session>();
+	}
+
 	#[inline]
 	pub fn get_header (&self, name: &str) -> Option<String> {
 		return self.req.headers.get(name);
@@ -34,6 +76,88 @@ impl HttpContext {
 		self.res_headers.set(name.to_string(), value);
 	}
 
+	/// Returns MIME type of request body without parameters, e.g. `application/json`
+	#[inline]
+	pub fn get_content_type (&self) -> Option<String> {
+		return self.req.headers.content_type();
+	}
+
+	#[inline]
+	pub fn body (&self) -> &[u8] {
+		return &self.req.body;
+	}
+
+	/// Decodes body as UTF-8, `content-type` must be `text/*`
+	pub fn body_text (&self) -> Result<&str, HttpCode> {
+		match self.get_content_type() {
+			Some(content_type) if content_type.starts_with("text/") => self.decode_text(),
+			_ => Err(HttpCode::UnsupportedMediaType)
+		}
+	}
+
+	/// Parses body as JSON, `content-type` must be `application/json` or `*/*+json`
+	pub fn body_json (&self) -> Result<JsonValue, HttpCode> {
+		match self.get_content_type() {
+			Some(content_type) if is_json_type(&content_type) => {
+				return json::parse(self.decode_text()?).map_err(|_| HttpCode::BadRequest);
+			}
+			_ => Err(HttpCode::UnsupportedMediaType)
+		}
+	}
+
+	/// Parses body as URL-encoded form, `content-type` must be `application/x-www-form-urlencoded`
+	pub fn form (&self) -> Result<UrlEncoded, HttpCode> {
+		match self.get_content_type() {
+			Some(content_type) if content_type == FORM_TYPE => {
+				let raw = str::from_utf8(&self.req.body).map_err(|_| HttpCode::BadRequest)?;
+				return Ok(UrlEncoded::parse(raw));
+			}
+			_ => Err(HttpCode::UnsupportedMediaType)
+		}
+	}
+
+	/// Returns fields and files of `multipart/form-data` body
+	pub fn multipart (&self) -> Result<&Multipart, HttpCode> {
+		return self.req.multipart.as_ref().ok_or(HttpCode::UnsupportedMediaType);
+	}
+
+	/// Decodes body using parser chosen by `content-type`, unknown types are returned as is
+	pub fn data (&self) -> Result<RequestData<'_>, HttpCode> {
+		let content_type = match self.get_content_type() {
+			Some(value) => value,
+			None => {
+				return if self.req.body.is_empty() {
+					Ok(RequestData::Empty)
+				} else {
+					Ok(RequestData::Raw(&self.req.body))
+				};
+			}
+		};
+
+		if is_json_type(&content_type) {
+			return self.body_json().map(RequestData::Json);
+		} else if content_type == FORM_TYPE {
+			return self.form().map(RequestData::Form);
+		} else if content_type == "multipart/form-data" {
+			return self.multipart().map(RequestData::Multipart);
+		} else if content_type.starts_with("text/") {
+			return self.decode_text().map(RequestData::Text);
+		} else {
+			return Ok(RequestData::Raw(&self.req.body));
+		}
+	}
+
+	fn decode_text (&self) -> Result<&str, HttpCode> {
+		match self.req.headers.get_param("content-type", "charset").map(|value| value.to_ascii_lowercase()) {
+			Some(charset) if charset != "utf-8" && charset != "utf8" && charset != "us-ascii" => {
+				return Err(HttpCode::UnsupportedMediaType);
+			}
+			_ => {}
+		}
+
+		return str::from_utf8(&self.req.body).map_err(|_| HttpCode::BadRequest);
+	}
+
 	pub fn text (self, message: &str) -> Response {
 		Response {
 			code: HttpCode::OK,
@@ -50,6 +174,25 @@ impl HttpContext {
 		}
 	}
 
+	pub fn json (self, value: &JsonValue) -> Response {
+		Response {
+			code: HttpCode::OK,
+			headers: self.res_headers.with_type("application/json"),
+			payload: ResponseType::Payload(value.dump().into_bytes())
+		}
+	}
+
+	/// Creates chunked response, `writer` is called after handler returns
+	pub fn stream<Writer> (self, content_type: &str, writer: Writer) -> Response
+	where Writer: FnOnce(&mut dyn Write) -> Result<(), Error> + Send + 'static
+	{
+		Response {
+			code: HttpCode::OK,
+			headers: self.res_headers.with_type(content_type),
+			payload: ResponseType::Stream(Box::new(writer))
+		}
+	}
+
 	pub fn redirect (mut self, target: &str) -> Response {
 		self.res_headers.set("location".to_string(), target.to_string());
 
@@ -64,3 +207,7 @@ impl HttpContext {
 		Response::drop()
 	}
 }
+
+fn is_json_type (content_type: &str) -> bool {
+	return content_type == "application/json" || content_type.ends_with("+json");
+}
@@ -1,2 +1,3 @@
+pub mod extensions;
 pub mod http;
 pub mod ws;
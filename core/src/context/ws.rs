@@ -1,31 +1,152 @@
-use std::{collections::HashMap, net::TcpStream};
-use bufstream::BufStream;
-use tungstenite::{WebSocket, protocol::Role};
+use std::{io::Error, sync::Arc};
+use json::JsonValue;
+use tungstenite::{WebSocket, protocol::{Role, CloseFrame, frame::coding::CloseCode}};
 use crate::http::entity::{Request, HttpConnection};
-use super::http::HttpContext;
+use crate::websocket::{Handshake, args::SocketError, channels::Channels, deflate::Inflater, sender::{SocketSender, SocketStream, SocketReader, into_io_error}};
+use super::{http::HttpContext, extensions::Extensions};
 
 pub struct SocketContext {
 	pub http: HttpContext,
-	pub stream: WebSocket<BufStream<TcpStream>>
+	pub stream: WebSocket<SocketStream>,
+	sender: SocketSender,
+	channels: Arc<Channels>,
+	/// Id of socket in channels registry
+	id: usize,
+	endpoint: String,
+	/// Subprotocol selected at handshake
+	protocol: Option<String>
 }
 
 impl SocketContext {
-	pub fn from<Connection: HttpConnection> (connection: Connection, req: Request) -> Self {
-		let http = HttpContext::from(&connection, req, HashMap::new());
+	pub fn from<Connection: HttpConnection> (connection: Connection, req: Request, mut handshake: Handshake, channels: &Arc<Channels>, endpoint: &str) -> Result<Self, Error> {
+		let http = HttpContext::from(&connection, req, handshake.take_params());
 		let stream = connection.into_stream();
-		let ws_stream = WebSocket::from_raw_socket(stream, Role::Server, None);
+		let sender = SocketSender::new(handshake.is_deflate);
+		sender.spawn_writer(stream.get_ref().try_clone()?);
 
-		SocketContext { http, stream: ws_stream }
+		return Ok(SocketContext::from_parts(http, SocketReader::Blocking(stream), Vec::new(), sender, handshake, channels, endpoint));
 	}
 
-	pub fn text (&mut self, event: &str, message: &str) {
-		let mut payload = event.to_string();
-		payload += ":";
-		payload += "[\"";
-		payload += &message.replace("\"", "\\\"");
-		payload += "\"]";
+	/// Socket of async engine, it's read without blocking and written by separate task.
+	/// `buffered` are bytes that were read from `stream` ahead of handshake completion,
+	/// `socket` is blocking handle of the same stream used to close connection.
+	#[cfg(feature = "async")]
+	pub(crate) fn from_async (http: HttpContext, stream: tokio::net::TcpStream, socket: std::net::TcpStream, buffered: Vec<u8>, handshake: Handshake, channels: &Arc<Channels>, endpoint: &str) -> Self {
+		let stream = Arc::new(stream);
+		let sender = SocketSender::new(handshake.is_deflate);
+		sender.spawn_async_writer(stream.clone(), socket);
 
-		// todo: report write error?
-		let _ = self.stream.write_message(tungstenite::Message::text(payload));
+		return SocketContext::from_parts(http, SocketReader::Async(stream), buffered, sender, handshake, channels, endpoint);
+	}
+
+	fn from_parts (mut http: HttpContext, reader: SocketReader, buffered: Vec<u8>, sender: SocketSender, handshake: Handshake, channels: &Arc<Channels>, endpoint: &str) -> Self {
+		let ws_stream = if handshake.is_deflate {
+			// Inflater has to see buffered frames too
+			WebSocket::from_raw_socket(SocketStream::new(reader, sender.clone(), Some(Inflater::new(buffered))), Role::Server, None)
+		} else {
+			WebSocket::from_partially_read(SocketStream::new(reader, sender.clone(), None), buffered, Role::Server, None)
+		};
+		let id = channels.add(endpoint, sender.clone());
+		let (protocol, extensions) = handshake.into_session();
+		http.extensions = extensions;
+
+		return SocketContext { http, stream: ws_stream, sender, channels: channels.clone(), id, endpoint: endpoint.to_string(), protocol };
+	}
+
+	/// Sends event in `event:["message"]` format
+	#[inline]
+	pub fn text (&self, event: &str, message: &str) -> Result<(), Error> {
+		return self.sender.text(event, message);
+	}
+
+	/// Sends event with JSON arguments, value that isn't array is sent as single argument
+	#[inline]
+	pub fn emit (&self, event: &str, args: JsonValue) -> Result<(), Error> {
+		return self.sender.emit(event, args);
+	}
+
+	/// Sends structured `error` event
+	#[inline]
+	pub fn error (&self, error: &SocketError) -> Result<(), Error> {
+		return self.sender.error(error);
+	}
+
+	#[inline]
+	pub fn binary (&self, data: Vec<u8>) -> Result<(), Error> {
+		return self.sender.binary(data);
+	}
+
+	/// Starts closing handshake, `close` handler is called when client confirms it.
+	/// Nothing can be sent after this call.
+	pub fn end (&mut self, code: u16, reason: &str) -> Result<(), Error> {
+		self.sender.set_closed();
+		let frame = CloseFrame { code: CloseCode::from(code), reason: reason.to_string().into() };
+		return self.stream.close(Some(frame)).map_err(into_io_error);
+	}
+
+	#[inline]
+	pub fn is_closed (&self) -> bool {
+		return self.sender.is_closed();
+	}
+
+	#[inline]
+	pub fn protocol (&self) -> Option<&str> {
+		return self.protocol.as_deref();
+	}
+
+	/// Per-connection values, they can be attached at handshake or by any handler
+	#[inline]
+	pub fn extensions (&self) -> &Extensions {
+		return &self.http.extensions;
+	}
+
+	#[inline]
+	pub fn extensions_mut (&mut self) -> &mut Extensions {
+		return &mut self.http.extensions;
+	}
+
+	/// Returns sender that can be moved to other threads
+	#[inline]
+	pub fn sender (&self) -> SocketSender {
+		return self.sender.clone();
+	}
+
+	#[inline]
+	pub fn channels (&self) -> &Arc<Channels> {
+		return &self.channels;
+	}
+
+	#[inline]
+	pub fn subscribe (&mut self, channel: &str) {
+		self.channels.subscribe(self.id, channel);
+	}
+
+	#[inline]
+	pub fn unsubscribe (&mut self, channel: &str) {
+		self.channels.unsubscribe(self.id, channel);
+	}
+
+	#[inline]
+	pub fn is_subscribed (&self, channel: &str) -> bool {
+		return self.channels.is_subscribed(self.id, channel);
+	}
+
+	/// Sends event to other sockets subscribed to `channel`
+	#[inline]
+	pub fn broadcast (&self, channel: &str, event: &str, message: &str) {
+		self.channels.broadcast_except(channel, Some(self.id), event, message);
+	}
+
+	/// Sends event to other sockets connected to the same endpoint
+	#[inline]
+	pub fn broadcast_endpoint (&self, event: &str, message: &str) {
+		self.channels.broadcast_endpoint_except(&self.endpoint, Some(self.id), event, message);
+	}
+}
+
+impl Drop for SocketContext {
+	fn drop (&mut self) {
+		self.sender.end();
+		self.channels.remove(self.id);
 	}
 }
@@ -0,0 +1,157 @@
+use std::io::{BufRead, Error, ErrorKind, Read, Write};
+use super::codes::HttpCode;
+
+const MAX_LINE_SIZE: u64 = 4096;
+
+/// Decodes body sent with `Transfer-Encoding: chunked`, trailers are skipped
+pub struct ChunkedReader<R: BufRead> {
+    inner: R,
+    remaining: u64,
+    is_done: bool
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    pub fn new (inner: R) -> Self {
+        ChunkedReader { inner, remaining: 0, is_done: false }
+    }
+
+    fn read_line (&mut self) -> Result<String, Error> {
+        let mut line = Vec::new();
+        (&mut self.inner).take(MAX_LINE_SIZE).read_until(b'\n', &mut line)?;
+        if !line.ends_with(b"\r\n") {
+            return Err(Error::new(ErrorKind::InvalidData, "Malformed chunk line"));
+        }
+
+        line.truncate(line.len() - 2);
+        return String::from_utf8(line).map_err(|_| Error::new(ErrorKind::InvalidData, "Malformed chunk line"));
+    }
+
+    fn read_chunk_size (&mut self) -> Result<u64, Error> {
+        let line = self.read_line()?;
+        // Chunk extensions are ignored
+        let size = line.split(';').next().unwrap_or("").trim();
+        return u64::from_str_radix(size, 16).map_err(|_| Error::new(ErrorKind::InvalidData, "Invalid chunk size"));
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read (&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.is_done || buf.is_empty() { return Ok(0); }
+
+        if self.remaining == 0 {
+            self.remaining = self.read_chunk_size()?;
+            if self.remaining == 0 {
+                while !self.read_line()?.is_empty() {}
+                self.is_done = true;
+                return Ok(0);
+            }
+        }
+
+        let max = buf.len().min(self.remaining.min(usize::MAX as u64) as usize);
+        let len = self.inner.read(&mut buf[..max])?;
+        if len == 0 { return Err(Error::from(ErrorKind::UnexpectedEof)); }
+
+        self.remaining -= len as u64;
+        if self.remaining == 0 && !self.read_line()?.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "Chunk is bigger than declared"));
+        }
+
+        return Ok(len);
+    }
+}
+
+/// Reads whole chunked body, `RequestEntityTooLarge` if it's bigger than `limit`
+pub(crate) fn read_chunked_body<R: BufRead> (reader: R, limit: usize) -> Result<Vec<u8>, HttpCode> {
+    let mut body = Vec::new();
+    if ChunkedReader::new(reader).take(limit as u64 + 1).read_to_end(&mut body).is_err() {
+        return Err(HttpCode::BadRequest);
+    }
+
+    if body.len() > limit { return Err(HttpCode::RequestEntityTooLarge); }
+    return Ok(body);
+}
+
+/// Encodes every write as separate chunk, `finish` must be called to terminate body
+pub struct ChunkedWriter<W: Write> {
+    inner: W
+}
+
+impl<W: Write> ChunkedWriter<W> {
+    pub fn new (inner: W) -> Self {
+        ChunkedWriter { inner }
+    }
+
+    pub fn finish (mut self) -> Result<W, Error> {
+        self.inner.write_all(b"0\r\n\r\n")?;
+        self.inner.flush()?;
+        return Ok(self.inner);
+    }
+}
+
+impl<W: Write> Write for ChunkedWriter<W> {
+    fn write (&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() { return Ok(0); }
+
+        write!(self.inner, "{:x}\r\n", buf.len())?;
+        self.inner.write_all(buf)?;
+        self.inner.write_all(b"\r\n")?;
+        return Ok(buf.len());
+    }
+
+    fn flush (&mut self) -> Result<(), Error> {
+        return self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Cursor};
+    use crate::http::codes::HttpCode;
+    use super::read_chunked_body;
+
+    /// Reader returns at most `capacity` bytes per read, so lines are split between reads
+    fn read (body: &str, capacity: usize, limit: usize) -> Result<Vec<u8>, HttpCode> {
+        return read_chunked_body(BufReader::with_capacity(capacity, Cursor::new(body.as_bytes())), limit);
+    }
+
+    #[test]
+    fn extensions_are_ignored () {
+        let body = read("4;name=value\r\nWiki\r\n5 ; a=\"b;c\" ; flag\r\npedia\r\n0;last\r\n\r\n", 64, 1024);
+        assert_eq!(body.ok(), Some(b"Wikipedia".to_vec()));
+    }
+
+    #[test]
+    fn trailers_are_skipped () {
+        let mut reader = BufReader::new(Cursor::new(b"3\r\nabc\r\n0\r\nExpires: never\r\nX-Sum: 1\r\n\r\nGET / HTTP/1.1".to_vec()));
+        assert_eq!(read_chunked_body(&mut reader, 1024).ok(), Some(b"abc".to_vec()));
+
+        // Next request on the same connection is left untouched
+        assert_eq!(reader.fill_buf().unwrap(), b"GET / HTTP/1.1");
+    }
+
+    #[test]
+    fn size_line_split_across_reads () {
+        let body = "1a\r\nabcdefghijklmnopqrstuvwxyz\r\nA;ext=1\r\n0123456789\r\n0\r\n\r\n";
+        for capacity in 1..=body.len() {
+            assert_eq!(read(body, capacity, 1024).ok(), Some(b"abcdefghijklmnopqrstuvwxyz0123456789".to_vec()), "read by {} bytes", capacity);
+        }
+    }
+
+    #[test]
+    fn body_over_limit_is_rejected () {
+        assert!(matches!(read("b\r\n0123456789a\r\n0\r\n\r\n", 4, 10), Err(HttpCode::RequestEntityTooLarge)));
+        assert!(matches!(read("6\r\n012345\r\n5\r\n6789a\r\n0\r\n\r\n", 4, 10), Err(HttpCode::RequestEntityTooLarge)));
+        // Declared size alone is enough, chunk isn't read to the end
+        assert!(matches!(read("ffffffff\r\n0123456789a", 4, 10), Err(HttpCode::RequestEntityTooLarge)));
+        assert_eq!(read("a\r\n0123456789\r\n0\r\n\r\n", 4, 10).ok(), Some(b"0123456789".to_vec()));
+    }
+
+    #[test]
+    fn invalid_size_is_bad_request () {
+        assert!(matches!(read("zz\r\nabc\r\n0\r\n\r\n", 4, 1024), Err(HttpCode::BadRequest)));
+        assert!(matches!(read("-3\r\nabc\r\n0\r\n\r\n", 4, 1024), Err(HttpCode::BadRequest)));
+        assert!(matches!(read("\r\nabc\r\n0\r\n\r\n", 4, 1024), Err(HttpCode::BadRequest)));
+        assert!(matches!(read("3\nabc\r\n0\r\n\r\n", 4, 1024), Err(HttpCode::BadRequest)));
+        assert!(matches!(read("2\r\nabc\r\n0\r\n\r\n", 4, 1024), Err(HttpCode::BadRequest)));
+    }
+}
@@ -0,0 +1,215 @@
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::app::config::Config;
+use super::{codes::HttpCode, entity::{Request, Response}};
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    /// Requires `Secure` in browsers
+    None
+}
+
+impl SameSite {
+    pub fn as_str (&self) -> &'static str {
+        match self {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None"
+        }
+    }
+}
+
+/// Outgoing cookie, it's formatted as `Set-Cookie` header value
+#[derive(Debug, Clone)]
+pub struct Cookie {
+    pub name: String,
+    pub value: String,
+    pub path: Option<String>,
+    pub domain: Option<String>,
+    /// Session cookie if `None`
+    pub max_age: Option<Duration>,
+    pub secure: bool,
+    pub http_only: bool,
+    pub same_site: Option<SameSite>
+}
+
+impl Cookie {
+    pub fn new (name: &str, value: &str) -> Self {
+        Cookie {
+            name: name.to_string(),
+            value: value.to_string(),
+            path: None,
+            domain: None,
+            max_age: None,
+            secure: false,
+            http_only: false,
+            same_site: None
+        }
+    }
+
+    /// Cookie that makes browser remove cookie with the same name, path is `/` and can be changed by `path`
+    pub fn removal (name: &str) -> Self {
+        Cookie::new(name, "").path("/").max_age(Duration::ZERO)
+    }
+
+    pub fn path (mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        return self;
+    }
+
+    pub fn domain (mut self, domain: &str) -> Self {
+        self.domain = Some(domain.to_string());
+        return self;
+    }
+
+    pub fn max_age (mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        return self;
+    }
+
+    pub fn secure (mut self, secure: bool) -> Self {
+        self.secure = secure;
+        return self;
+    }
+
+    pub fn http_only (mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        return self;
+    }
+
+    pub fn same_site (mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        return self;
+    }
+}
+
+impl fmt::Display for Cookie {
+    /// Characters that can break header are dropped
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", clean(&self.name, is_token_char), clean(&self.value, is_value_char))?;
+        if let Some(path) = &self.path { write!(f, "; Path={}", clean(path, is_attribute_char))?; }
+        if let Some(domain) = &self.domain { write!(f, "; Domain={}", clean(domain, is_attribute_char))?; }
+        if let Some(max_age) = &self.max_age { write!(f, "; Max-Age={}", max_age.as_secs())?; }
+        if self.secure { write!(f, "; Secure")?; }
+        if self.http_only { write!(f, "; HttpOnly")?; }
+        if let Some(same_site) = &self.same_site { write!(f, "; SameSite={}", same_site.as_str())?; }
+        return Ok(());
+    }
+}
+
+/// Cookies of request and cookies that will be set by response
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    incoming: Vec<(String, String)>,
+    outgoing: OutgoingCookies
+}
+
+/// Cookies set by handler, server keeps a handle to add them to any response, including ones built without context
+#[derive(Debug, Default, Clone)]
+pub(crate) struct OutgoingCookies(Arc<Mutex<Vec<Cookie>>>);
+
+impl OutgoingCookies {
+    /// Adds `set-cookie` header for every cookie
+    pub fn apply (&self, res: &mut Response) {
+        for cookie in self.0.lock().unwrap().drain(..) {
+            res.headers.append("set-cookie".to_string(), cookie.to_string());
+        }
+    }
+}
+
+impl CookieJar {
+    pub fn from_request (req: &Request) -> Self {
+        let incoming = req.headers.get_all("cookie")
+            .flat_map(|header| header.split(';'))
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (name.trim().to_string(), value.trim().trim_matches('"').to_string()))
+            .filter(|(name, _)| !name.is_empty())
+            .collect();
+
+        CookieJar { incoming, outgoing: OutgoingCookies::default() }
+    }
+
+    /// Value of request cookie, first one if client sent several
+    pub fn get (&self, name: &str) -> Option<&str> {
+        return self.incoming.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str());
+    }
+
+    /// Request cookies as name and value pairs
+    pub fn iter (&self) -> impl Iterator<Item = (&str, &str)> {
+        return self.incoming.iter().map(|(name, value)| (name.as_str(), value.as_str()));
+    }
+
+    /// Adds cookie to response, previous cookie with the same name and path is replaced
+    pub fn set (&mut self, cookie: Cookie) {
+        let mut outgoing = self.outgoing.0.lock().unwrap();
+        outgoing.retain(|set| set.name != cookie.name || set.path != cookie.path);
+        outgoing.push(cookie);
+    }
+
+    /// Removes cookie with path `/` from client
+    #[inline]
+    pub fn remove (&mut self, name: &str) {
+        self.set(Cookie::removal(name));
+    }
+
+    /// Value of signed request cookie, `None` if signature is wrong or `cookies.secret` isn't configured
+    pub fn get_signed (&self, name: &str) -> Option<&str> {
+        let (value, signature) = self.get(name)?.rsplit_once('.')?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).ok()?;
+        let mac = cookie_mac(name, value)?;
+        return mac.verify_slice(&signature).ok().map(|_| value);
+    }
+
+    /// Adds cookie with HMAC signature appended to value, client can read it but can't change it.
+    /// Fails with `InternalServerError` if `cookies.secret` isn't configured.
+    pub fn set_signed (&mut self, mut cookie: Cookie) -> Result<(), HttpCode> {
+        let mac = cookie_mac(&cookie.name, &cookie.value).ok_or(HttpCode::InternalServerError)?;
+        let signature = base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD);
+        cookie.value = format!("{}.{}", cookie.value, signature);
+        self.set(cookie);
+        return Ok(());
+    }
+
+    /// Cookies that will be sent as `set-cookie` headers
+    pub fn outgoing (&self) -> Vec<Cookie> {
+        return self.outgoing.0.lock().unwrap().clone();
+    }
+
+    #[inline]
+    pub(crate) fn outgoing_handle (&self) -> OutgoingCookies {
+        return self.outgoing.clone();
+    }
+}
+
+/// Name is signed too, so signed value can't be moved to other cookie
+fn cookie_mac (name: &str, value: &str) -> Option<HmacSha256> {
+    let secret = Config::get().cookies.secret.as_ref()?;
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(name.as_bytes());
+    mac.update(b"=");
+    mac.update(value.as_bytes());
+    return Some(mac);
+}
+
+fn clean (value: &str, is_allowed: fn(char) -> bool) -> String {
+    return value.chars().filter(|ch| is_allowed(*ch)).collect();
+}
+
+fn is_token_char (ch: char) -> bool {
+    return ch.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(ch);
+}
+
+fn is_value_char (ch: char) -> bool {
+    return ch.is_ascii_graphic() && !"\",;\\".contains(ch);
+}
+
+fn is_attribute_char (ch: char) -> bool {
+    return (ch.is_ascii_graphic() || ch == ' ') && ch != ';';
+}
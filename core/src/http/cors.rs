@@ -1,9 +1,10 @@
-use std::process;
+use std::{process, sync::Arc};
 use json::JsonValue;
-use crate::{app::config::Config, utils::{json_read_array, log::log_error}};
-use super::entity::{Request, Response};
+use crate::{app::{config::Config, middleware::Flow}, context::http::HttpContext, utils::{json_read_array, log::log_error}};
+use super::{codes::HttpCode, entity::{HttpMethod, Request, Response}};
 
 static mut CORS: Option<CorsConfig> = None;
+#[derive(Clone)]
 pub struct CorsConfig {
 	origin: String,
 	methods: String,
@@ -52,38 +53,182 @@ impl CorsConfig {
 			return Self::init();
 		}
 	}
+
+	/// Allowed origins, empty or `*` allows any
+	pub fn origin (mut self, origin: &[&str]) -> Self {
+		self.origin = origin.join(",");
+		return self;
+	}
+
+	pub fn methods (mut self, methods: &[&str]) -> Self {
+		self.methods = methods.join(",");
+		return self;
+	}
+
+	pub fn headers (mut self, headers: &[&str]) -> Self {
+		self.headers = headers.join(",");
+		return self;
+	}
+
+	/// Max age of preflight response in seconds
+	pub fn ttl (mut self, ttl: u32) -> Self {
+		self.ttl = ttl.to_string();
+		return self;
+	}
+
+	/// Empty or `*` origin allows any, otherwise origin must be in comma separated list
+	pub fn is_origin_allowed (&self, origin: &str) -> bool {
+		if self.is_any_origin() { return true; }
+		return self.origin.split(',').any(|allowed| allowed.trim() == origin);
+	}
+
+	fn is_any_origin (&self) -> bool {
+		return self.origin.is_empty() || self.origin == "*";
+	}
 }
 
 pub struct Cors {
-	origin: String
+	origin: String,
+	/// Policy of route group, `None` means policy from config
+	policy: Option<Arc<CorsConfig>>
 }
 
 impl Cors {
 	pub fn new (req: &Request) -> Self {
 		Cors {
-			origin: req.headers.get("origin").unwrap_or("*".to_string())
+			origin: req.headers.get("origin").unwrap_or("*".to_string()),
+			policy: None
 		}
 	}
 
-	pub fn apply_origin_check (self, res: &mut Response, default: &String) {
-		if self.origin.is_empty() {
-			res.headers.set("Access-Control-Allow-Origin".to_string(), default.clone());
-		} else {
+	pub fn with_policy (mut self, policy: Arc<CorsConfig>) -> Self {
+		self.policy = Some(policy);
+		return self;
+	}
+
+	/// Origin is sent back only if policy allows it
+	pub fn apply_origin_check (self, res: &mut Response, policy: &CorsConfig) {
+		// Allowed origin is echoed back, so cached response must not be reused for other origins
+		if (self.origin != "*" || !policy.is_any_origin()) && !res.headers.has_token("vary", "origin") {
+			res.headers.append("Vary".to_string(), "Origin".to_string());
+		}
+
+		if policy.is_origin_allowed(&self.origin) {
 			res.headers.set("Access-Control-Allow-Origin".to_string(), self.origin);
 		}
 	}
 
 	pub fn apply_normal (self, res: &mut Response) {
-		let policy = CorsConfig::get();
+		let policy = self.policy.clone();
+		let policy = policy.as_deref().unwrap_or_else(|| CorsConfig::get());
 		res.headers.set("Access-Control-Expose-Headers".to_string(), policy.headers.clone());
-		self.apply_origin_check(res, &policy.origin);
+		self.apply_origin_check(res, policy);
 	}
 
 	pub fn apply_preflight (self, res: &mut Response) {
-		let policy = CorsConfig::get();
+		let policy = self.policy.clone();
+		let policy = policy.as_deref().unwrap_or_else(|| CorsConfig::get());
 		res.headers.set("Access-Control-Allow-Methods".to_string(), policy.methods.clone());
 		res.headers.set("Access-Control-Allow-Headers".to_string(), policy.headers.clone());
 		res.headers.set("Access-Control-Max-Age".to_string(), policy.ttl.clone());
-		self.apply_origin_check(res, &policy.origin);
+		self.apply_origin_check(res, policy);
+	}
+}
+
+/// Built-in middleware, answers preflight requests and adds CORS headers to other responses,
+/// including `OPTIONS` requests handled by routes. Policy of route group is taken from context extensions.
+pub fn cors_middleware (ctx: &mut HttpContext) -> Flow {
+	let mut cors = Cors::new(&ctx.req);
+	if let Some(policy) = ctx.extensions.get::<Arc<CorsConfig>>() {
+		cors = cors.with_policy(policy.clone());
+	}
+
+	if is_preflight(&ctx.req) {
+		let mut res = Response::from_status(HttpCode::OK);
+		cors.apply_preflight(&mut res);
+		return Flow::Respond(res);
+	}
+
+	return Flow::after(move |res| cors.apply_normal(res));
+}
+
+/// Preflight is `OPTIONS` request sent by browser before cross-origin request
+fn is_preflight (req: &Request) -> bool {
+	return req.method == HttpMethod::OPTIONS && req.headers.has("origin") && req.headers.has("access-control-request-method");
+}
+
+#[cfg(test)]
+mod tests {
+	use std::net::{IpAddr, Ipv4Addr};
+	use crate::app::{App, server::route_request};
+	use crate::context::http::HttpContext;
+	use std::sync::Arc;
+	use crate::http::codes::HttpCode;
+	use crate::http::entity::{HttpMethod, Request, Response, ResponseType};
+	use super::{Cors, CorsConfig, is_preflight};
+
+	fn options_request (headers: &[(&str, &str)]) -> Request {
+		let mut req = Request::new(HttpMethod::OPTIONS, "/item/5".to_string());
+		for (name, value) in headers {
+			req.headers.append_normal(name.to_string(), value.to_string());
+		}
+
+		return req;
+	}
+
+	#[test]
+	fn preflight_requires_origin_and_method () {
+		assert!(is_preflight(&options_request(&[("Origin", "http://a"), ("Access-Control-Request-Method", "PUT")])));
+		assert!(!is_preflight(&options_request(&[("Origin", "http://a")])));
+		assert!(!is_preflight(&options_request(&[])));
+	}
+
+	#[test]
+	fn options_route_is_reachable () {
+		let mut app = App::new();
+		app.router.on(HttpMethod::OPTIONS, "/item/{id}".to_string(), |ctx| {
+			let msg = format!("Options of item #{}", ctx.params["id"]);
+			return ctx.text(&msg);
+		});
+
+		let req = options_request(&[("Origin", "http://a")]);
+		let (route, params) = route_request(&app, &req);
+		let route = route.ok().expect("OPTIONS route isn't matched");
+		let mut ctx = HttpContext::new(IpAddr::V4(Ipv4Addr::LOCALHOST), req, params);
+
+		let (_, stopped) = app.middlewares.run(&mut ctx, Some(route));
+		assert!(stopped.is_none(), "OPTIONS request is answered by CORS middleware");
+
+		let res = route.call(ctx);
+		assert!(matches!(res.payload, ResponseType::Payload(body) if body == b"Options of item #5"));
+	}
+
+	fn cors_response (origin: Option<&str>, allowed: &str) -> Response {
+		let mut req = Request::new(HttpMethod::GET, "/item/5".to_string());
+		if let Some(origin) = origin {
+			req.headers.append_normal("origin".to_string(), origin.to_string());
+		}
+
+		let policy = CorsConfig { origin: allowed.to_string(), methods: "GET".to_string(), headers: String::new(), ttl: "60".to_string() };
+		let mut res = Response::from_status(HttpCode::OK);
+		res.headers.append("Vary".to_string(), "Accept-Encoding".to_string());
+		Cors::new(&req).with_policy(Arc::new(policy)).apply_normal(&mut res);
+		return res;
+	}
+
+	#[test]
+	fn reflected_origin_varies_by_origin () {
+		let res = cors_response(Some("https://a.example"), "*");
+		assert_eq!(res.headers.get("access-control-allow-origin").as_deref(), Some("https://a.example"));
+		assert_eq!(res.headers.get_all("vary").collect::<Vec<_>>(), ["Accept-Encoding", "Origin"]);
+
+		// Disallowed origin gets different response too
+		let res = cors_response(Some("https://b.example"), "https://a.example");
+		assert!(!res.headers.has("access-control-allow-origin"));
+		assert!(res.headers.has_token("vary", "origin"));
+
+		let res = cors_response(None, "*");
+		assert_eq!(res.headers.get("access-control-allow-origin").as_deref(), Some("*"));
+		assert!(!res.headers.has_token("vary", "origin"));
 	}
 }
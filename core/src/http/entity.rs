@@ -1,23 +1,56 @@
 use core::slice;
-use std::io::Error;
+use std::io::{Error, Write};
 use std::net::{SocketAddr, IpAddr, TcpStream};
+use std::str::FromStr;
+use std::time::Duration;
 use bufstream::BufStream;
 use crate::http::codes::HttpCode;
+use crate::http::multipart::Multipart;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum HttpMethod {
     GET,
+    HEAD,
     POST,
-    OPTIONS
+    PUT,
+    PATCH,
+    DELETE,
+    OPTIONS,
+    CONNECT,
+    TRACE
 }
 
-impl HttpMethod {
-    pub fn from_str (method: &str) -> Option<HttpMethod> {
+impl FromStr for HttpMethod {
+    type Err = ();
+
+    fn from_str (method: &str) -> Result<Self, Self::Err> {
         match method {
-            "GET" => Some(HttpMethod::GET),
-            "POST" => Some(HttpMethod::POST),
-            "OPTIONS" => Some(HttpMethod::OPTIONS),
-            _ => None
+            "GET" => Ok(HttpMethod::GET),
+            "HEAD" => Ok(HttpMethod::HEAD),
+            "POST" => Ok(HttpMethod::POST),
+            "PUT" => Ok(HttpMethod::PUT),
+            "PATCH" => Ok(HttpMethod::PATCH),
+            "DELETE" => Ok(HttpMethod::DELETE),
+            "OPTIONS" => Ok(HttpMethod::OPTIONS),
+            "CONNECT" => Ok(HttpMethod::CONNECT),
+            "TRACE" => Ok(HttpMethod::TRACE),
+            _ => Err(())
+        }
+    }
+}
+
+impl HttpMethod {
+    pub fn as_str (&self) -> &'static str {
+        match self {
+            HttpMethod::GET => "GET",
+            HttpMethod::HEAD => "HEAD",
+            HttpMethod::POST => "POST",
+            HttpMethod::PUT => "PUT",
+            HttpMethod::PATCH => "PATCH",
+            HttpMethod::DELETE => "DELETE",
+            HttpMethod::OPTIONS => "OPTIONS",
+            HttpMethod::CONNECT => "CONNECT",
+            HttpMethod::TRACE => "TRACE"
         }
     }
 }
@@ -28,6 +61,7 @@ pub struct HttpHeader {
     pub value: String
 }
 
+/// Headers in order they were added, names are case-insensitive and may repeat
 #[derive(Debug)]
 pub struct HttpHeaders {
     contents: Vec<HttpHeader>
@@ -49,40 +83,171 @@ impl HttpHeaders {
         return self;
     }
 
+    /// Replaces all values of the header with one value
     pub fn set (&mut self, name: String, value: String) {
-        if let Some(header) = self.contents.iter_mut().find(|h| h.name == name) {
-            header.value = value.trim_start().to_string();
-        } else {
+        let value = value.trim().to_string();
+        let mut is_found = false;
+        // First value is replaced in place, so header keeps its position
+        self.contents.retain_mut(|h| {
+            if !h.name.eq_ignore_ascii_case(&name) { return true; }
+            if is_found { return false; }
+
+            is_found = true;
+            h.value = value.clone();
+            return true;
+        });
+
+        if !is_found {
             self.contents.push(HttpHeader { name, value });
         }
     }
 
+    /// Adds header even if there is one with the same name, e.g. `set-cookie`
+    pub fn append (&mut self, name: String, value: String) {
+        self.contents.push(HttpHeader { name, value: value.trim().to_string() });
+    }
+
+    /// Adds incoming header, name is lower-cased so headers look the same regardless of client
+    pub fn append_normal (&mut self, name: String, value: String) {
+        self.append(name.trim().to_ascii_lowercase(), value);
+    }
+
+    /// Same as `set`, but name is lower-cased
     pub fn set_normal (&mut self, name: String, value: String) {
-        self.set(name.to_ascii_lowercase(), value.trim_start().to_string());
+        self.set(name.to_ascii_lowercase(), value);
     }
 
+    /// Sets header only if it's missing
     pub fn set_default (&mut self, name: String, value: String) {
-        if let None = self.contents.iter_mut().find(|h| h.name == name) {
-            self.contents.push(HttpHeader { name, value });
+        if !self.has(&name) {
+            self.append(name, value);
         }
     }
 
-    pub fn remove (&mut self, name: String) {
-        let lower_name = name.to_ascii_lowercase();
-        if let Some(i) = self.contents.iter().position(|h| h.name == lower_name) {
-            self.contents.remove(i);
-        }
+    /// Removes all values of the header
+    pub fn remove (&mut self, name: &str) {
+        self.contents.retain(|h| !h.name.eq_ignore_ascii_case(name));
+    }
+
+    #[inline]
+    pub fn has (&self, name: &str) -> bool {
+        return self.contents.iter().any(|h| h.name.eq_ignore_ascii_case(name));
     }
 
+    /// Returns first value of the header
     pub fn get (&self, name: &str) -> Option<String> {
-        for header in self {
-            if header.name == name {
-                return Some(header.value.clone())
+        return self.get_all(name).next().map(str::to_string);
+    }
+
+    /// Returns values of repeated header in order they were received
+    pub fn get_all<'a> (&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        return self.contents.iter().filter(move |h| h.name.eq_ignore_ascii_case(name)).map(|h| h.value.as_str());
+    }
+
+    /// Items of comma-separated header, repeated headers are joined as one list
+    pub fn get_list<'a> (&'a self, name: &'a str) -> impl Iterator<Item = &'a str> {
+        return self.get_all(name).flat_map(|value| value.split(',')).map(str::trim).filter(|item| !item.is_empty());
+    }
+
+    /// Checks whether comma-separated header contains the token, e.g. `upgrade` in `connection`
+    pub fn has_token (&self, name: &str, token: &str) -> bool {
+        return self.get_list(name).any(|item| item.eq_ignore_ascii_case(token));
+    }
+
+    /// Reads parameter of the header value, e.g. `boundary` of `multipart/form-data; boundary=...`.
+    /// Quoted value can contain `;` and backslash escapes, e.g. `filename="a;b.txt"`.
+    pub fn get_param (&self, name: &str, param: &str) -> Option<String> {
+        let header = self.get(name)?;
+        let mut rest = header.split_once(';')?.1;
+
+        while !rest.is_empty() {
+            let key_end = rest.find(['=', ';']).unwrap_or(rest.len());
+            let key = rest[..key_end].trim();
+            rest = &rest[key_end..];
+
+            // Parameter without value
+            if !rest.starts_with('=') {
+                rest = rest.strip_prefix(';').unwrap_or(rest);
+                continue;
+            }
+
+            rest = rest[1..].trim_start();
+            let value;
+            if let Some(quoted) = rest.strip_prefix('"') {
+                let (unquoted, len) = read_quoted(quoted);
+                value = unquoted;
+                rest = &quoted[len..];
+                rest = rest.find(';').map_or("", |index| &rest[(index + 1)..]);
+            } else {
+                let end = rest.find(';').unwrap_or(rest.len());
+                value = rest[..end].trim().to_string();
+                rest = rest.get((end + 1)..).unwrap_or("");
             }
+
+            if key.eq_ignore_ascii_case(param) { return Some(value); }
         }
 
         return None;
     }
+
+    /// Body length, `BadRequest` if value isn't a number or repeated headers don't match
+    pub fn content_length (&self) -> Result<Option<usize>, HttpCode> {
+        let mut length = None;
+        for value in self.get_list("content-length") {
+            let value = value.parse::<usize>().map_err(|_| HttpCode::BadRequest)?;
+            if length.is_some_and(|length| length != value) { return Err(HttpCode::BadRequest); }
+            length = Some(value);
+        }
+
+        return Ok(length);
+    }
+
+    #[inline]
+    pub fn set_content_length (&mut self, length: usize) {
+        self.set("content-length".to_string(), length.to_string());
+    }
+
+    /// MIME type without parameters in lower case, e.g. `application/json`
+    pub fn content_type (&self) -> Option<String> {
+        let header = self.get("content-type")?;
+        let essence = header.split(';').next().unwrap_or("").trim();
+        return if essence.is_empty() { None } else { Some(essence.to_ascii_lowercase()) };
+    }
+
+    #[inline]
+    pub fn set_content_type (&mut self, content_type: &str) {
+        self.set("content-type".to_string(), content_type.to_string());
+    }
+
+    /// Media ranges of `accept` header sorted by preference, ranges with `q=0` are skipped
+    pub fn accept (&self) -> Vec<String> {
+        let mut ranges: Vec<(String, f32)> = self.get_list("accept").filter_map(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let range = params.next()?.to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.split_once('='))
+                .find(|(key, _)| key.trim().eq_ignore_ascii_case("q"))
+                .map(|(_, value)| value.trim().parse::<f32>().unwrap_or(0.0))
+                .unwrap_or(1.0);
+
+            return if quality > 0.0 && !range.is_empty() { Some((range, quality)) } else { None };
+        }).collect();
+
+        // Stable sort keeps order of client for ranges with the same quality
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+        return ranges.into_iter().map(|(range, _)| range).collect();
+    }
+
+    /// Checks whether client accepts the MIME type, missing `accept` header means any type
+    pub fn accepts (&self, content_type: &str) -> bool {
+        if !self.has("accept") { return true; }
+
+        let content_type = content_type.to_ascii_lowercase();
+        let main_type = content_type.split('/').next().unwrap_or("");
+        return self.accept().iter().any(|range| {
+            return range == "*/*" || *range == content_type || range.strip_suffix("/*") == Some(main_type);
+        });
+    }
 }
 
 impl<'a> IntoIterator for &'a HttpHeaders {
@@ -99,6 +264,10 @@ pub trait HttpConnection: Sized + Send + Sync {
     fn into_stream (self) -> BufStream<TcpStream>;
 
     fn parse (&mut self) -> ParsingResult;
+    /// Limits time of waiting for the next request
+    fn set_timeout (&mut self, timeout: Option<Duration>) -> Result<(), Error>;
+    /// Checks whether connection can be reused after responding to `req`
+    fn is_keep_alive (&self, req: &Request) -> bool;
     fn respond (&mut self, res: Response) -> Result<(), Error>;
     fn disconnect (self) -> Result<(), Error>;
 }
@@ -109,6 +278,27 @@ pub trait HttpEngine<Connection: HttpConnection> {
     fn handle_connection (socket: (TcpStream, SocketAddr)) -> Connection;
 }
 
+#[cfg(feature = "async")]
+#[async_trait::async_trait]
+pub trait AsyncHttpConnection: Sized + Send {
+    fn get_address (&self) -> IpAddr;
+    /// Converts connection into socket, bytes that were already read ahead are returned too
+    fn into_stream (self) -> (tokio::net::TcpStream, Vec<u8>);
+
+    async fn parse (&mut self) -> ParsingResult;
+    /// Limits time of waiting for the next request and every read of it
+    fn set_timeout (&mut self, timeout: Option<Duration>);
+    /// Checks whether connection can be reused after responding to `req`
+    fn is_keep_alive (&self, req: &Request) -> bool;
+    async fn respond (&mut self, res: Response) -> Result<(), Error>;
+    async fn disconnect (self) -> Result<(), Error>;
+}
+
+#[cfg(feature = "async")]
+pub trait AsyncHttpEngine<Connection: AsyncHttpConnection> {
+    fn handle_connection (socket: (tokio::net::TcpStream, SocketAddr)) -> Connection;
+}
+
 pub enum ParsingResult {
     Complete(Request),
     Partial,
@@ -146,6 +336,23 @@ impl Response {
             payload: ResponseType::Drop
         }
     }
+
+    /// Removes payload, but keeps its length, as response to `HEAD` request should
+    pub fn into_head (mut self) -> Self {
+        match &self.payload {
+            ResponseType::Payload(payload) => {
+                self.headers.set_content_length(payload.len());
+                self.payload = ResponseType::NoContent;
+            }
+            ResponseType::Stream(_) => {
+                self.headers.set("transfer-encoding".to_string(), "chunked".to_string());
+                self.payload = ResponseType::NoContent;
+            }
+            _ => {}
+        }
+
+        return self;
+    }
 }
 
 #[derive(Debug)]
@@ -154,6 +361,8 @@ pub struct Request {
     pub query: String,
     pub headers: HttpHeaders,
     pub method: HttpMethod,
+    pub body: Vec<u8>,
+    pub multipart: Option<Multipart>
 }
 
 impl Request {
@@ -167,14 +376,37 @@ impl Request {
             path,
             query,
             method,
-            headers: HttpHeaders::empty()
+            headers: HttpHeaders::empty(),
+            body: Vec::new(),
+            multipart: None
         }
     }
 }
 
+/// Writes response body incrementally, every write is sent to client as separate chunk
+pub type StreamWriter = Box<dyn FnOnce(&mut dyn Write) -> Result<(), Error> + Send>;
+
 pub enum ResponseType {
     NoContent,
     Payload(Vec<u8>),
+    Stream(StreamWriter),
     Upgrade,
     Drop
 }
+
+/// Unescapes quoted string that follows opening quote, returns its value and length including closing quote
+fn read_quoted (quoted: &str) -> (String, usize) {
+    let mut value = String::new();
+    let mut chars = quoted.char_indices();
+
+    while let Some((index, ch)) = chars.next() {
+        match ch {
+            '"' => return (value, index + 1),
+            '\\' => if let Some((_, escaped)) = chars.next() { value.push(escaped); },
+            _ => value.push(ch)
+        }
+    }
+
+    // Closing quote is missing
+    return (value, quoted.len());
+}
@@ -1,3 +1,7 @@
+pub mod chunked;
 pub mod codes;
+pub mod cookie;
 pub mod cors;
 pub mod entity;
+pub mod multipart;
+pub mod urlencoded;
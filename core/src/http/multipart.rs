@@ -0,0 +1,383 @@
+use std::fs;
+use std::io::{BufRead, Cursor, Error, Read, Write};
+use std::path::Path;
+use tempfile::NamedTempFile;
+use crate::app::config::Limits;
+use super::codes::HttpCode;
+use super::entity::HttpHeaders;
+use super::urlencoded::UrlEncoded;
+
+const MAX_HEADERS_SIZE: usize = 8 * 1024;
+
+#[derive(Debug)]
+pub struct Multipart {
+    pub fields: UrlEncoded,
+    pub files: Vec<MultipartFile>
+}
+
+impl Multipart {
+    pub fn get_file (&self, name: &str) -> Option<&MultipartFile> {
+        return self.files.iter().find(|file| file.name == name);
+    }
+
+    pub fn get_files (&self, name: &str) -> Vec<&MultipartFile> {
+        return self.files.iter().filter(|file| file.name == name).collect();
+    }
+}
+
+#[derive(Debug)]
+enum FileContents {
+    Memory(Vec<u8>),
+    Temp(NamedTempFile)
+}
+
+#[derive(Debug)]
+pub struct MultipartFile {
+    /// Name of the form field
+    pub name: String,
+    /// File name sent by client, should not be trusted
+    pub filename: String,
+    pub content_type: String,
+    pub size: usize,
+    contents: FileContents
+}
+
+impl MultipartFile {
+    /// Path of temporary file, if file was too big to be kept in memory.
+    /// Temporary file is removed together with request.
+    pub fn path (&self) -> Option<&Path> {
+        match &self.contents {
+            FileContents::Memory(_) => None,
+            FileContents::Temp(file) => Some(file.path())
+        }
+    }
+
+    pub fn open (&self) -> Result<Box<dyn Read + '_>, Error> {
+        match &self.contents {
+            FileContents::Memory(buffer) => Ok(Box::new(Cursor::new(buffer.as_slice()))),
+            FileContents::Temp(file) => Ok(Box::new(file.reopen()?))
+        }
+    }
+
+    pub fn read_all (&self) -> Result<Vec<u8>, Error> {
+        let mut buffer = Vec::with_capacity(self.size);
+        self.open()?.read_to_end(&mut buffer)?;
+        return Ok(buffer);
+    }
+
+    /// Moves file to the `target` path
+    pub fn persist<P: AsRef<Path>> (self, target: P) -> Result<(), Error> {
+        match self.contents {
+            FileContents::Memory(buffer) => fs::write(target, buffer),
+            FileContents::Temp(file) => {
+                if let Err(err) = file.persist(&target) {
+                    // Renaming is not possible between different file systems
+                    fs::copy(err.file.path(), &target)?;
+                }
+
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Returns boundary if request has `multipart/form-data` content type
+pub fn get_boundary (headers: &HttpHeaders) -> Option<String> {
+    if headers.content_type()? != "multipart/form-data" { return None; }
+
+    return headers.get_param("content-type", "boundary").filter(|boundary| !boundary.is_empty());
+}
+
+/// Reads `multipart/form-data` body part by part, big files are written to temporary files
+pub fn parse_multipart<R: BufRead> (reader: R, boundary: &str, limits: &Limits) -> Result<Multipart, HttpCode> {
+    let parser = MultipartParser {
+        reader,
+        limits,
+        delimiter: format!("\r\n--{}", boundary).into_bytes(),
+        // First delimiter isn't prefixed with CRLF
+        carry: b"\r\n".to_vec(),
+        consumed: 0
+    };
+
+    return parser.parse();
+}
+
+struct MultipartParser<'a, R: BufRead> {
+    reader: R,
+    limits: &'a Limits,
+    delimiter: Vec<u8>,
+    /// Tail of previous buffer that can contain beginning of delimiter
+    carry: Vec<u8>,
+    consumed: usize
+}
+
+impl<'a, R: BufRead> MultipartParser<'a, R> {
+    fn parse (mut self) -> Result<Multipart, HttpCode> {
+        let mut result = Multipart { fields: UrlEncoded::empty(), files: Vec::new() };
+
+        let mut preamble = PartWriter::new(PartSink::Discard, self.limits.multipart_total, None);
+        self.read_part(&mut preamble)?;
+
+        while !self.read_delimiter_tail()? {
+            let headers = self.read_headers()?;
+            let disposition = headers.get("content-disposition").ok_or(HttpCode::BadRequest)?;
+            if !disposition.trim_start().to_ascii_lowercase().starts_with("form-data") {
+                return Err(HttpCode::BadRequest);
+            }
+
+            let name = headers.get_param("content-disposition", "name").ok_or(HttpCode::BadRequest)?;
+            if let Some(filename) = headers.get_param("content-disposition", "filename") {
+                let mut writer = PartWriter::new(
+                    PartSink::Memory(Vec::new()),
+                    self.limits.multipart_part,
+                    Some(self.limits.multipart_memory)
+                );
+                self.read_part(&mut writer)?;
+
+                let contents = match writer.sink {
+                    PartSink::Memory(buffer) => FileContents::Memory(buffer),
+                    PartSink::Temp(file) => FileContents::Temp(file),
+                    PartSink::Discard => unreachable!()
+                };
+
+                result.files.push(MultipartFile {
+                    name,
+                    filename,
+                    content_type: headers.get("content-type").unwrap_or("application/octet-stream".to_string()),
+                    size: writer.size,
+                    contents
+                });
+            } else {
+                let mut writer = PartWriter::new(PartSink::Memory(Vec::new()), self.limits.multipart_part, None);
+                self.read_part(&mut writer)?;
+
+                if let PartSink::Memory(buffer) = writer.sink {
+                    let value = String::from_utf8(buffer).map_err(|_| HttpCode::BadRequest)?;
+                    result.fields.push(name, value);
+                }
+            }
+        }
+
+        // Skip epilogue
+        loop {
+            let len = self.reader.fill_buf().map_err(|_| HttpCode::BadRequest)?.len();
+            if len == 0 { break; }
+            self.consume(len)?;
+        }
+
+        return Ok(result);
+    }
+
+    /// Reads part contents until delimiter, delimiter itself is consumed too
+    fn read_part (&mut self, writer: &mut PartWriter) -> Result<(), HttpCode> {
+        loop {
+            let buffer = self.reader.fill_buf().map_err(|_| HttpCode::BadRequest)?;
+            if buffer.is_empty() { return Err(HttpCode::BadRequest); }
+
+            let buffer_len = buffer.len();
+            let carry_len = self.carry.len();
+            let mut window = std::mem::take(&mut self.carry);
+            window.extend_from_slice(buffer);
+
+            if let Some(index) = find_sequence(&window, &self.delimiter) {
+                writer.write(&window[..index])?;
+                return self.consume(index + self.delimiter.len() - carry_len);
+            }
+
+            let split = window.len().saturating_sub(self.delimiter.len() - 1);
+            writer.write(&window[..split])?;
+            self.carry = window.split_off(split);
+            self.consume(buffer_len)?;
+        }
+    }
+
+    /// Returns `true` if it was the closing delimiter
+    fn read_delimiter_tail (&mut self) -> Result<bool, HttpCode> {
+        let mut tail = [0u8; 2];
+        self.reader.read_exact(&mut tail).map_err(|_| HttpCode::BadRequest)?;
+        self.count(2)?;
+
+        match &tail {
+            b"--" => Ok(true),
+            b"\r\n" => Ok(false),
+            _ => Err(HttpCode::BadRequest)
+        }
+    }
+
+    fn read_headers (&mut self) -> Result<HttpHeaders, HttpCode> {
+        let mut headers = HttpHeaders::empty();
+        let mut total = 0usize;
+
+        loop {
+            let mut line = Vec::new();
+            let len = (&mut self.reader)
+                .take((MAX_HEADERS_SIZE - total) as u64)
+                .read_until(b'\n', &mut line)
+                .map_err(|_| HttpCode::BadRequest)?;
+
+            self.count(len)?;
+            total += len;
+
+            if !line.ends_with(b"\r\n") {
+                return Err(if total >= MAX_HEADERS_SIZE { HttpCode::RequestHeaderFieldsTooLarge } else { HttpCode::BadRequest });
+            }
+
+            line.truncate(len - 2);
+            if line.is_empty() { break; }
+
+            let line = String::from_utf8(line).map_err(|_| HttpCode::BadRequest)?;
+            match line.split_once(':') {
+                Some((name, value)) => headers.append_normal(name.to_string(), value.to_string()),
+                None => return Err(HttpCode::BadRequest)
+            }
+        }
+
+        return Ok(headers);
+    }
+
+    #[inline]
+    fn consume (&mut self, len: usize) -> Result<(), HttpCode> {
+        self.reader.consume(len);
+        return self.count(len);
+    }
+
+    #[inline]
+    fn count (&mut self, len: usize) -> Result<(), HttpCode> {
+        self.consumed += len;
+        return if self.consumed > self.limits.multipart_total { Err(HttpCode::RequestEntityTooLarge) } else { Ok(()) };
+    }
+}
+
+enum PartSink {
+    Discard,
+    Memory(Vec<u8>),
+    Temp(NamedTempFile)
+}
+
+struct PartWriter {
+    sink: PartSink,
+    size: usize,
+    limit: usize,
+    spill_after: Option<usize>
+}
+
+impl PartWriter {
+    fn new (sink: PartSink, limit: usize, spill_after: Option<usize>) -> Self {
+        PartWriter { sink, size: 0, limit, spill_after }
+    }
+
+    fn write (&mut self, data: &[u8]) -> Result<(), HttpCode> {
+        self.size += data.len();
+        if self.size > self.limit { return Err(HttpCode::RequestEntityTooLarge); }
+
+        match &mut self.sink {
+            PartSink::Discard => {}
+            PartSink::Memory(buffer) => {
+                if matches!(self.spill_after, Some(threshold) if self.size > threshold) {
+                    let mut file = NamedTempFile::new().map_err(|_| HttpCode::InternalServerError)?;
+                    file.write_all(buffer).map_err(|_| HttpCode::InternalServerError)?;
+                    file.write_all(data).map_err(|_| HttpCode::InternalServerError)?;
+                    self.sink = PartSink::Temp(file);
+                } else {
+                    buffer.extend_from_slice(data);
+                }
+            }
+            PartSink::Temp(file) => {
+                file.write_all(data).map_err(|_| HttpCode::InternalServerError)?;
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+fn find_sequence (haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if haystack.len() < needle.len() { return None; }
+    return haystack.windows(needle.len()).position(|window| window == needle);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Cursor};
+    use crate::app::config::Limits;
+    use crate::http::codes::HttpCode;
+    use crate::http::entity::HttpHeaders;
+    use super::{Multipart, get_boundary, parse_multipart};
+
+    const BOUNDARY: &str = "XyZ-boundary";
+
+    fn limits (part: usize, memory: usize) -> Limits {
+        return Limits { body: 1024 * 1024, multipart_part: part, multipart_total: 1024 * 1024, multipart_memory: memory };
+    }
+
+    fn body (parts: &[(&str, &str)], is_closed: bool) -> Vec<u8> {
+        let mut body = String::new();
+        for (disposition, contents) in parts {
+            body += &format!("--{}\r\ncontent-disposition: form-data; {}\r\n\r\n{}\r\n", BOUNDARY, disposition, contents);
+        }
+
+        if is_closed { body += &format!("--{}--\r\n", BOUNDARY); }
+        return body.into_bytes();
+    }
+
+    /// Reader returns at most `capacity` bytes per read, so delimiters are split between reads
+    fn parse (body: Vec<u8>, capacity: usize, limits: &Limits) -> Result<Multipart, HttpCode> {
+        return parse_multipart(BufReader::with_capacity(capacity, Cursor::new(body)), BOUNDARY, limits);
+    }
+
+    #[test]
+    fn boundary_split_across_reads () {
+        let body = body(&[("name=\"title\"", "Hello\r\n--XyZ"), ("name=\"file\"; filename=\"a.txt\"", "file contents")], true);
+        for capacity in 1..=body.len() {
+            let multipart = parse(body.clone(), capacity, &limits(1024, 1024)).ok().expect("body isn't parsed");
+            assert_eq!(multipart.fields.get("title"), Some("Hello\r\n--XyZ"), "read by {} bytes", capacity);
+            assert_eq!(multipart.get_file("file").unwrap().read_all().unwrap(), b"file contents");
+        }
+    }
+
+    #[test]
+    fn quoted_parameters_keep_separators () {
+        let body = body(&[("name=\"file\"; filename=\"a;b \\\"c\\\".txt\"", "data")], true);
+        let multipart = parse(body, 64, &limits(1024, 1024)).ok().expect("body isn't parsed");
+        assert_eq!(multipart.get_file("file").unwrap().filename, "a;b \"c\".txt");
+
+        let mut headers = HttpHeaders::empty();
+        headers.append_normal("content-type".to_string(), "multipart/form-data; boundary=\"a;b\"; charset=utf-8".to_string());
+        assert_eq!(get_boundary(&headers).as_deref(), Some("a;b"));
+        assert_eq!(headers.get_param("content-type", "charset").as_deref(), Some("utf-8"));
+    }
+
+    #[test]
+    fn part_over_limit_is_rejected () {
+        let file = body(&[("name=\"file\"; filename=\"a.txt\"", "0123456789")], true);
+        assert!(matches!(parse(file.clone(), 4, &limits(9, 1024)), Err(HttpCode::RequestEntityTooLarge)));
+        assert!(parse(file, 4, &limits(10, 1024)).is_ok());
+
+        let field = body(&[("name=\"field\"", "0123456789")], true);
+        assert!(matches!(parse(field, 4, &limits(9, 1024)), Err(HttpCode::RequestEntityTooLarge)));
+    }
+
+    #[test]
+    fn big_file_spills_to_temp_file () {
+        let body = body(&[("name=\"small\"; filename=\"a.txt\"", "0123"), ("name=\"big\"; filename=\"b.txt\"", "0123456789")], true);
+        let multipart = parse(body, 4, &limits(1024, 4)).ok().expect("body isn't parsed");
+
+        let small = multipart.get_file("small").unwrap();
+        assert!(small.path().is_none());
+
+        let big = multipart.get_file("big").unwrap();
+        assert!(big.path().is_some_and(|path| path.exists()));
+        assert_eq!(big.size, 10);
+        assert_eq!(big.read_all().unwrap(), b"0123456789");
+    }
+
+    #[test]
+    fn missing_closing_boundary_is_rejected () {
+        let unclosed = body(&[("name=\"title\"", "Hello")], false);
+        assert!(matches!(parse(unclosed, 4, &limits(1024, 1024)), Err(HttpCode::BadRequest)));
+
+        let mut truncated = body(&[("name=\"title\"", "Hello")], true);
+        truncated.truncate(truncated.len() - 6);
+        assert!(matches!(parse(truncated, 4, &limits(1024, 1024)), Err(HttpCode::BadRequest)));
+    }
+}
@@ -0,0 +1,91 @@
+use core::slice;
+use std::str::FromStr;
+use crate::utils::url_decode;
+use super::codes::HttpCode;
+
+/// Key-value pairs from query string or `application/x-www-form-urlencoded` body.
+/// Repeated keys and array keys (`ids[]=1&ids[]=2`) are collected into one list.
+#[derive(Debug)]
+pub struct UrlEncoded {
+    contents: Vec<(String, Vec<String>)>
+}
+
+impl UrlEncoded {
+    pub fn empty () -> Self {
+        UrlEncoded { contents: Vec::new() }
+    }
+
+    pub fn parse (input: &str) -> Self {
+        let mut result = UrlEncoded::empty();
+        for pair in input.split('&') {
+            if pair.is_empty() { continue; }
+
+            let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let mut name = url_decode(name);
+            if name.ends_with("[]") {
+                name.truncate(name.len() - 2);
+            }
+
+            result.push(name, url_decode(value));
+        }
+
+        return result;
+    }
+
+    pub fn push (&mut self, name: String, value: String) {
+        if let Some(entry) = self.contents.iter_mut().find(|entry| entry.0 == name) {
+            entry.1.push(value);
+        } else {
+            self.contents.push((name, vec![value]));
+        }
+    }
+
+    #[inline]
+    pub fn has (&self, name: &str) -> bool {
+        return self.contents.iter().any(|entry| entry.0 == name);
+    }
+
+    /// Returns first value of the key
+    pub fn get (&self, name: &str) -> Option<&str> {
+        return self.get_all(name).first().map(String::as_str);
+    }
+
+    pub fn get_all (&self, name: &str) -> &[String] {
+        for entry in &self.contents {
+            if entry.0 == name {
+                return &entry.1;
+            }
+        }
+
+        return &[];
+    }
+
+    /// Converts first value of the key, `BadRequest` is returned if conversion failed
+    pub fn get_as<T: FromStr> (&self, name: &str) -> Result<Option<T>, HttpCode> {
+        match self.get(name) {
+            Some(value) => value.parse::<T>().map(Some).map_err(|_| HttpCode::BadRequest),
+            None => Ok(None)
+        }
+    }
+
+    /// Same as `get_as`, but missing key is also treated as `BadRequest`
+    pub fn require<T: FromStr> (&self, name: &str) -> Result<T, HttpCode> {
+        return self.get_as(name)?.ok_or(HttpCode::BadRequest);
+    }
+
+    pub fn get_all_as<T: FromStr> (&self, name: &str) -> Result<Vec<T>, HttpCode> {
+        return self.get_all(name)
+            .iter()
+            .map(|value| value.parse::<T>().map_err(|_| HttpCode::BadRequest))
+            .collect();
+    }
+}
+
+impl<'a> IntoIterator for &'a UrlEncoded {
+    type Item = &'a (String, Vec<String>);
+    type IntoIter = slice::Iter<'a, (String, Vec<String>)>;
+
+    fn into_iter (self) -> Self::IntoIter {
+        return self.contents.iter();
+    }
+}
@@ -1,11 +1,15 @@
-use std::io::{Error, Read, Write};
+use std::io::{BufRead, BufReader, Error, Read, Write};
+use std::mem;
 use std::net::{SocketAddr, IpAddr, TcpStream, Shutdown};
+use std::time::Duration;
 use byteorder::ReadBytesExt;
 use byteorder::WriteBytesExt;
-use std::str::FromStr;
 use bufstream::BufStream;
 use dc_macro::assert_stream;
+use crate::app::config::Config;
 use crate::http::codes::HttpCode;
+use crate::http::chunked::{ChunkedReader, ChunkedWriter, read_chunked_body};
+use crate::http::multipart::{get_boundary, parse_multipart};
 use crate::http::entity::{HttpConnection, HttpEngine, HttpMethod, ParsingResult, Request, Response, ResponseType};
 use crate::utils::stream::StreamUtils;
 
@@ -29,7 +33,7 @@ impl Http1Connection {
         Http1Connection {
             stream: BufStream::new(socket.0),
             address: socket.1.ip(),
-            version_minor: '\0'
+            version_minor: '1'
         }
     }
 }
@@ -39,93 +43,227 @@ impl HttpConnection for Http1Connection {
     fn into_stream (self) -> BufStream<TcpStream> { self.stream }
 
     fn parse (&mut self) -> ParsingResult {
-        let method = self.stream.read_string_before(' ');
-        if method.is_none() { return ParsingResult::Invalid; }
+        let mut req = match read_head(&mut self.stream, &mut self.version_minor) {
+            Ok(req) => req,
+            Err(error) => return error.into()
+        };
 
-        let method = HttpMethod::from_str(method.unwrap().as_str());
-        if method.is_none() { return ParsingResult::Error(HttpCode::MethodNotAllowed) }
+        if let Err(result) = read_body(&mut self.stream, &mut req) {
+            return result.into();
+        }
+
+        return ParsingResult::Complete(req);
+    }
 
-        let path = self.stream.read_string_before(' ');
-        if path.is_none() { return ParsingResult::Error(HttpCode::RequestEntityTooLarge); }
+    fn set_timeout (&mut self, timeout: Option<Duration>) -> Result<(), Error> {
+        return self.stream.get_ref().set_read_timeout(timeout);
+    }
 
-        // let mut a = [0u8; 7];
-        // self.stream.read_exact(&mut a);
-        assert_stream!(self.stream, "HTTP/1.", ParsingResult::Invalid);
-        self.version_minor = self.stream.read_u8().unwrap() as char;
+    #[inline]
+    fn is_keep_alive (&self, req: &Request) -> bool {
+        return is_keep_alive(self.version_minor, req);
+    }
 
-        let mut req = Request::new(method.unwrap(), path.unwrap());
+    fn respond (&mut self, mut res: Response) -> Result<(), Error> {
+        if let ResponseType::Drop = res.payload {
+            return Ok(());
+        }
 
-        assert_stream!(self.stream, "\r", ParsingResult::Invalid);
-        loop {
-            assert_stream!(self.stream, "\n", ParsingResult::Invalid);
-            let mut header_name = Vec::new();
-            header_name.push(self.stream.read_u8().unwrap());
-            header_name.push(self.stream.read_u8().unwrap());
+        prepare_response(&mut res, self.version_minor)?;
+        write_head(&res, self.version_minor, &mut self.stream)?;
 
-            if header_name[0] == '\r' as u8 && header_name[1] == '\n' as u8 {
-                break;
+        match res.payload {
+            ResponseType::Payload(payload) => {
+                self.stream.write(&payload)?;
+            }
+            ResponseType::Stream(writer) => {
+                let mut chunked = ChunkedWriter::new(&mut self.stream);
+                writer(&mut chunked)?;
+                chunked.finish()?;
             }
+            _ => {}
+        }
 
-            let header_read_result = self.stream.read_before(':' as u8, &mut header_name);
-            if header_read_result.is_none() { return ParsingResult::Error(HttpCode::RequestHeaderFieldsTooLarge) }
+        return self.stream.flush();
+    }
 
-            let header_value = self.stream.read_string_before('\r');
-            if header_value.is_none() { return ParsingResult::Error(HttpCode::RequestHeaderFieldsTooLarge) }
+    fn disconnect (self) -> Result<(), Error> {
+        let stream = self.stream.into_inner()?;
+        return stream.shutdown(Shutdown::Both);
+    }
+}
 
-            let header_name = unsafe { String::from_utf8_unchecked(header_name) };
-            req.headers.set_normal(header_name, header_value.unwrap());
-        }
+/// Reads request line and headers
+pub(crate) fn read_head<R: BufRead> (stream: &mut R, version_minor: &mut char) -> Result<Request, ParseError> {
+    let method = stream.read_string_before(' ');
+    if method.is_none() { return Err(ParseError::Invalid); }
+
+    let method = method.unwrap().parse::<HttpMethod>();
+    if method.is_err() { return Err(ParseError::Respond(HttpCode::NotImplemented)) }
+
+    let path = stream.read_string_before(' ');
+    if path.is_none() { return Err(ParseError::Respond(HttpCode::RequestEntityTooLarge)); }
 
-        if matches!(req.method, HttpMethod::POST) {
-            let length_header = req.headers.get("Content-Length");
-            if length_header.is_none() { return ParsingResult::Error(HttpCode::BadRequest) }
+    assert_stream!(stream, "HTTP/1.", Err(ParseError::Invalid));
+    let version = stream.read_u8();
+    if version.is_err() { return Err(ParseError::Invalid); }
+    *version_minor = version.unwrap() as char;
 
-            let size = usize::from_str(length_header.unwrap().as_str());
-            if size.is_err() { return ParsingResult::Error(HttpCode::BadRequest) }
+    let mut req = Request::new(method.unwrap(), path.unwrap());
 
-            let size = size.unwrap();
-            let mut body_raw = Vec::<u8>::with_capacity(size);
-            unsafe { body_raw.set_len(size); }
+    assert_stream!(stream, "\r", Err(ParseError::Invalid));
+    loop {
+        assert_stream!(stream, "\n", Err(ParseError::Invalid));
+        let mut header_name = vec![0u8; 2];
+        if stream.read_exact(&mut header_name).is_err() { return Err(ParseError::Invalid); }
 
-            let body_raw = body_raw.as_mut_slice();
-            self.stream.read_exact(body_raw).unwrap();
-            println!("Content:\n{:?}", body_raw);
+        if header_name[0] == '\r' as u8 && header_name[1] == '\n' as u8 {
+            break;
         }
 
-        return ParsingResult::Complete(req);
+        let header_read_result = stream.read_before(':' as u8, &mut header_name);
+        if header_read_result.is_none() { return Err(ParseError::Respond(HttpCode::RequestHeaderFieldsTooLarge)) }
+
+        let header_value = stream.read_string_before('\r');
+        if header_value.is_none() { return Err(ParseError::Respond(HttpCode::RequestHeaderFieldsTooLarge)) }
+
+        let header_name = unsafe { String::from_utf8_unchecked(header_name) };
+        req.headers.append_normal(header_name, header_value.unwrap());
     }
 
-    fn respond (&mut self, res: Response) -> Result<(), Error> {
-        if let ResponseType::Drop = res.payload {
-            return Ok(());
+    return Ok(req);
+}
+
+/// Reason of parsing failure, turned into `ParsingResult` by connection
+pub(crate) enum ParseError {
+    Respond(HttpCode),
+    Invalid
+}
+
+impl From<ParseError> for ParsingResult {
+    fn from (error: ParseError) -> Self {
+        match error {
+            ParseError::Respond(code) => ParsingResult::Error(code),
+            ParseError::Invalid => ParsingResult::Invalid
         }
+    }
+}
 
-        self.stream.write(b"HTTP/1.")?;
-        self.stream.write_u8(self.version_minor as u8)?;
-        self.stream.write_u8(' ' as u8)?;
-        let (res_code, res_reason) = res.code.get_description();
+pub(crate) enum BodyFraming {
+    None,
+    Length(usize),
+    Chunked
+}
+
+/// Detects how request body is delimited, validating related headers
+pub(crate) fn get_body_framing (req: &Request) -> Result<BodyFraming, ParseError> {
+    if let Some(encoding) = req.headers.get("transfer-encoding") {
+        if !encoding.trim().eq_ignore_ascii_case("chunked") { return Err(ParseError::Respond(HttpCode::NotImplemented)) }
+        // Ambiguous body length, may be request smuggling attempt
+        if req.headers.has("content-length") { return Err(ParseError::Respond(HttpCode::BadRequest)) }
+
+        return Ok(BodyFraming::Chunked);
+    } else if let Some(size) = req.headers.content_length().map_err(ParseError::Respond)? {
+        return Ok(BodyFraming::Length(size));
+    } else {
+        // Request without framing headers has no body
+        return Ok(BodyFraming::None);
+    }
+}
 
-        self.stream.write(res_code.as_bytes())?;
-        self.stream.write_u8(' ' as u8)?;
-        self.stream.write(res_reason.as_bytes())?;
+/// Reads request body into `req.body` or `req.multipart`
+pub(crate) fn read_body<R: BufRead> (stream: &mut R, req: &mut Request) -> Result<(), ParseError> {
+    let limits = &Config::get().limits;
+    let boundary = get_boundary(&req.headers);
 
-        for header in &res.headers {
-            self.stream.write(b"\r\n")?;
-            self.stream.write(header.name.as_bytes())?;
-            self.stream.write(b": ")?;
-            self.stream.write(header.value.as_bytes())?;
+    match get_body_framing(req)? {
+        BodyFraming::None => {}
+        BodyFraming::Chunked => {
+            if let Some(boundary) = boundary {
+                let reader = BufReader::new(ChunkedReader::new(stream));
+                req.multipart = Some(parse_multipart(reader, &boundary, limits).map_err(ParseError::Respond)?);
+            } else {
+                req.body = read_chunked_body(stream, limits.body).map_err(ParseError::Respond)?;
+            }
         }
+        BodyFraming::Length(size) => {
+            if let Some(boundary) = boundary {
+                if size > limits.multipart_total { return Err(ParseError::Respond(HttpCode::RequestEntityTooLarge)) }
+                req.multipart = Some(parse_multipart(stream.take(size as u64), &boundary, limits).map_err(ParseError::Respond)?);
+            } else {
+                if size > limits.body { return Err(ParseError::Respond(HttpCode::RequestEntityTooLarge)) }
 
-        self.stream.write(b"\r\n\r\n")?;
-        if let ResponseType::Payload(payload) = res.payload {
-            self.stream.write(&payload)?;
+                let mut body = vec![0u8; size];
+                if stream.read_exact(&mut body).is_err() { return Err(ParseError::Invalid); }
+                req.body = body;
+            }
         }
+    }
 
-        return Ok(());
+    return Ok(());
+}
+
+pub(crate) fn is_keep_alive (version_minor: char, req: &Request) -> bool {
+    if version_minor == '0' {
+        return req.headers.has_token("connection", "keep-alive");
+    } else {
+        return !req.headers.has_token("connection", "close");
     }
+}
 
-    fn disconnect (self) -> Result<(), Error> {
-        let stream = self.stream.into_inner()?;
-        return stream.shutdown(Shutdown::Both);
+/// Sets headers describing response body
+pub(crate) fn prepare_response (res: &mut Response, version_minor: char) -> Result<(), Error> {
+    // HTTP/1.0 doesn't support chunked encoding, so body is buffered
+    if version_minor == '0' {
+        if let ResponseType::Stream(_) = res.payload {
+            if let ResponseType::Stream(writer) = mem::replace(&mut res.payload, ResponseType::NoContent) {
+                let mut payload = Vec::new();
+                writer(&mut payload)?;
+                res.payload = ResponseType::Payload(payload);
+            }
+        }
+    }
+
+    match &res.payload {
+        ResponseType::Payload(payload) => {
+            res.headers.set_content_length(payload.len());
+        }
+        ResponseType::Stream(_) => {
+            res.headers.remove("content-length");
+            res.headers.set("transfer-encoding".to_string(), "chunked".to_string());
+        }
+        ResponseType::NoContent if has_length(&res.code) && !res.headers.has("transfer-encoding") => {
+            res.headers.set_default("content-length".to_string(), "0".to_string());
+        }
+        _ => {}
     }
+
+    return Ok(());
+}
+
+pub(crate) fn write_head<W: Write> (res: &Response, version_minor: char, stream: &mut W) -> Result<(), Error> {
+    stream.write(b"HTTP/1.")?;
+    stream.write_u8(version_minor as u8)?;
+    stream.write_u8(' ' as u8)?;
+    let (res_code, res_reason) = res.code.get_description();
+
+    stream.write(res_code.as_bytes())?;
+    stream.write_u8(' ' as u8)?;
+    stream.write(res_reason.as_bytes())?;
+
+    for header in &res.headers {
+        stream.write(b"\r\n")?;
+        stream.write(header.name.as_bytes())?;
+        stream.write(b": ")?;
+        stream.write(header.value.as_bytes())?;
+    }
+
+    stream.write(b"\r\n\r\n")?;
+    return Ok(());
+}
+
+/// Informational, `204 No Content` and `304 Not Modified` responses can't have body
+fn has_length (code: &HttpCode) -> bool {
+    let (code, _) = code.get_description();
+    return !code.starts_with('1') && code != "204" && code != "304";
 }
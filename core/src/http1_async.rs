@@ -0,0 +1,241 @@
+use std::future::Future;
+use std::io::{BufReader, Cursor, Error, ErrorKind, Seek, SeekFrom, Write};
+use std::net::{SocketAddr, IpAddr};
+use std::time::Duration;
+use async_trait::async_trait;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::runtime::Handle;
+use tokio::task::block_in_place;
+use crate::app::config::Config;
+use crate::http::codes::HttpCode;
+use crate::http::chunked::ChunkedWriter;
+use crate::http::multipart::{get_boundary, parse_multipart};
+use crate::http::entity::{AsyncHttpConnection, AsyncHttpEngine, ParsingResult, Request, Response, ResponseType};
+use crate::http1::{read_head, get_body_framing, is_keep_alive, prepare_response, write_head, BodyFraming, ParseError};
+
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+const MAX_LINE_SIZE: u64 = 4096;
+
+#[derive(Copy, Clone)]
+pub struct AsyncHttp1Engine;
+
+impl AsyncHttpEngine<AsyncHttp1Connection> for AsyncHttp1Engine {
+    fn handle_connection (socket: (TcpStream, SocketAddr)) -> AsyncHttp1Connection {
+        AsyncHttp1Connection::new(socket)
+    }
+}
+
+pub struct AsyncHttp1Connection {
+    stream: tokio::io::BufReader<TcpStream>,
+    address: IpAddr,
+    version_minor: char,
+    /// Limits every read, so slow body that keeps progressing isn't dropped
+    timeout: Option<Duration>
+}
+
+impl AsyncHttp1Connection {
+    fn new (socket: (TcpStream, SocketAddr)) -> Self {
+        AsyncHttp1Connection {
+            stream: tokio::io::BufReader::new(socket.0),
+            address: socket.1.ip(),
+            version_minor: '1',
+            timeout: None
+        }
+    }
+
+    /// Request head is read as a whole and then parsed by the blocking parser
+    async fn read_head (&mut self) -> Result<Request, ParseError> {
+        let mut head = Vec::new();
+        loop {
+            let limit = (MAX_HEAD_SIZE - head.len()) as u64;
+            let len = match with_timeout(self.timeout, (&mut self.stream).take(limit).read_until(b'\n', &mut head)).await {
+                Ok(len) => len,
+                Err(_) => return Err(ParseError::Invalid)
+            };
+
+            if head.ends_with(b"\r\n\r\n") { break; }
+            if head.len() >= MAX_HEAD_SIZE { return Err(ParseError::Respond(HttpCode::RequestHeaderFieldsTooLarge)); }
+            if len == 0 { return Err(ParseError::Invalid); }
+        }
+
+        return read_head(&mut Cursor::new(head), &mut self.version_minor);
+    }
+
+    async fn read_body (&mut self, req: &mut Request) -> Result<(), ParseError> {
+        let limits = &Config::get().limits;
+        let framing = get_body_framing(req)?;
+        if let BodyFraming::None = framing { return Ok(()); }
+
+        if let Some(boundary) = get_boundary(&req.headers) {
+            // Body is received before parsing, big bodies are kept in temporary file
+            let mut body = tempfile::spooled_tempfile(limits.multipart_memory);
+            self.read_raw_body(framing, limits.multipart_total, &mut body).await?;
+            if body.seek(SeekFrom::Start(0)).is_err() { return Err(ParseError::Respond(HttpCode::InternalServerError)); }
+
+            let multipart = block_in_place(|| parse_multipart(BufReader::new(body), &boundary, limits));
+            req.multipart = Some(multipart.map_err(ParseError::Respond)?);
+        } else {
+            let mut body = Vec::new();
+            self.read_raw_body(framing, limits.body, &mut body).await?;
+            req.body = body;
+        }
+
+        return Ok(());
+    }
+
+    async fn read_raw_body<W: Write + Send> (&mut self, framing: BodyFraming, limit: usize, sink: &mut W) -> Result<(), ParseError> {
+        match framing {
+            BodyFraming::None => {}
+            BodyFraming::Length(size) => {
+                if size > limit { return Err(ParseError::Respond(HttpCode::RequestEntityTooLarge)); }
+                self.copy_exact(size as u64, sink).await?;
+            }
+            BodyFraming::Chunked => {
+                let mut total = 0u64;
+                loop {
+                    let line = self.read_line().await?;
+                    // Chunk extensions are ignored
+                    let size = line.split(';').next().unwrap_or("").trim();
+                    let size = u64::from_str_radix(size, 16).map_err(|_| ParseError::Respond(HttpCode::BadRequest))?;
+
+                    if size == 0 {
+                        while !self.read_line().await?.is_empty() {}
+                        break;
+                    }
+
+                    total += size;
+                    if total > limit as u64 { return Err(ParseError::Respond(HttpCode::RequestEntityTooLarge)); }
+
+                    self.copy_exact(size, sink).await?;
+                    if !self.read_line().await?.is_empty() { return Err(ParseError::Respond(HttpCode::BadRequest)); }
+                }
+            }
+        }
+
+        return Ok(());
+    }
+
+    async fn copy_exact<W: Write + Send> (&mut self, size: u64, sink: &mut W) -> Result<(), ParseError> {
+        let mut buffer = [0u8; 8192];
+        let mut remaining = size;
+
+        while remaining > 0 {
+            let max = remaining.min(buffer.len() as u64) as usize;
+            let len = with_timeout(self.timeout, self.stream.read(&mut buffer[..max])).await.map_err(|_| ParseError::Invalid)?;
+            if len == 0 { return Err(ParseError::Invalid); }
+
+            sink.write_all(&buffer[..len]).map_err(|_| ParseError::Respond(HttpCode::InternalServerError))?;
+            remaining -= len as u64;
+        }
+
+        return Ok(());
+    }
+
+    async fn read_line (&mut self) -> Result<String, ParseError> {
+        let mut line = Vec::new();
+        if with_timeout(self.timeout, (&mut self.stream).take(MAX_LINE_SIZE).read_until(b'\n', &mut line)).await.is_err() {
+            return Err(ParseError::Invalid);
+        }
+
+        if !line.ends_with(b"\r\n") { return Err(ParseError::Respond(HttpCode::BadRequest)); }
+        line.truncate(line.len() - 2);
+        return String::from_utf8(line).map_err(|_| ParseError::Respond(HttpCode::BadRequest));
+    }
+}
+
+/// Same as read timeout of blocking socket
+async fn with_timeout<T, F: Future<Output = Result<T, Error>>> (timeout: Option<Duration>, future: F) -> Result<T, Error> {
+    match timeout {
+        Some(duration) => tokio::time::timeout(duration, future).await.unwrap_or_else(|_| Err(Error::from(ErrorKind::TimedOut))),
+        None => future.await
+    }
+}
+
+#[async_trait]
+impl AsyncHttpConnection for AsyncHttp1Connection {
+    fn get_address (&self) -> IpAddr { self.address }
+
+    fn into_stream (self) -> (TcpStream, Vec<u8>) {
+        let buffered = self.stream.buffer().to_vec();
+        return (self.stream.into_inner(), buffered);
+    }
+
+    async fn parse (&mut self) -> ParsingResult {
+        let mut req = match self.read_head().await {
+            Ok(req) => req,
+            Err(error) => return error.into()
+        };
+
+        if let Err(result) = self.read_body(&mut req).await {
+            return result.into();
+        }
+
+        return ParsingResult::Complete(req);
+    }
+
+    #[inline]
+    fn set_timeout (&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    #[inline]
+    fn is_keep_alive (&self, req: &Request) -> bool {
+        return is_keep_alive(self.version_minor, req);
+    }
+
+    async fn respond (&mut self, mut res: Response) -> Result<(), Error> {
+        if let ResponseType::Drop = res.payload {
+            return Ok(());
+        }
+
+        prepare_response(&mut res, self.version_minor)?;
+        let mut head = Vec::new();
+        write_head(&res, self.version_minor, &mut head)?;
+
+        let stream = self.stream.get_mut();
+        match res.payload {
+            ResponseType::Payload(payload) => {
+                head.extend_from_slice(&payload);
+                stream.write_all(&head).await?;
+            }
+            ResponseType::Stream(writer) => {
+                stream.write_all(&head).await?;
+                // Stream writers are blocking, so they're called outside of async context
+                block_in_place(|| {
+                    let handle = Handle::current();
+                    let mut chunked = ChunkedWriter::new(BlockingWriter { stream: &mut *stream, handle: &handle });
+                    writer(&mut chunked)?;
+                    return chunked.finish().map(|_| ());
+                })?;
+            }
+            _ => {
+                stream.write_all(&head).await?;
+            }
+        }
+
+        return stream.flush().await;
+    }
+
+    async fn disconnect (self) -> Result<(), Error> {
+        let mut stream = self.stream.into_inner();
+        return stream.shutdown().await;
+    }
+}
+
+/// Writes to async stream from blocking code
+struct BlockingWriter<'a> {
+    stream: &'a mut TcpStream,
+    handle: &'a Handle
+}
+
+impl<'a> Write for BlockingWriter<'a> {
+    fn write (&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.handle.block_on(self.stream.write_all(buf))?;
+        return Ok(buf.len());
+    }
+
+    fn flush (&mut self) -> Result<(), Error> {
+        return self.handle.block_on(self.stream.flush());
+    }
+}
@@ -1,15 +1,28 @@
-use std::sync::Mutex;
-use app::App;
+use std::sync::Arc;
+use app::{App, handle::ServerHandle};
 
 pub mod app;
 pub mod http;
 pub mod http1;
+#[cfg(feature = "async")]
+pub mod http1_async;
 pub mod websocket;
 pub mod context;
+pub mod session;
 pub mod utils;
 
 pub extern crate dc_macro;
 
-pub fn spawn_server (app: &'static Mutex<App>) {
-    app::server::start_server(app);
+/// Freezes `app` and starts serving it in background thread,
+/// routes and handlers can't be changed after this call
+pub fn spawn_server (app: App) -> ServerHandle {
+    let app = Arc::new(app);
+    return ServerHandle::spawn(move |state| app::server::start_server(app, state));
+}
+
+/// Same as `spawn_server`, but connections are served by tokio runtime
+#[cfg(feature = "async")]
+pub fn spawn_async_server (app: App) -> ServerHandle {
+    let app = Arc::new(app);
+    return ServerHandle::spawn(move |state| app::async_server::start_server(app, state));
 }
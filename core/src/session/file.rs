@@ -0,0 +1,106 @@
+use std::fs;
+use std::io::{Error, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use json::JsonValue;
+use tempfile::NamedTempFile;
+use super::{SessionStore, is_valid_id};
+
+/// Keeps every session in `<id>.json` file inside directory, sessions survive restarts.
+/// Expiration time is kept in `<id>.expires`, so touching session never rewrites its value.
+pub struct FileStore {
+    path: PathBuf
+}
+
+impl FileStore {
+    /// Directory is created on first save
+    pub fn new (path: PathBuf) -> Self {
+        FileStore { path }
+    }
+
+    fn file_path (&self, id: &str, extension: &str) -> Option<PathBuf> {
+        if !is_valid_id(id) { return None; }
+        return Some(self.path.join(format!("{}.{}", id, extension)));
+    }
+
+    /// Expiration time in seconds since epoch
+    fn read_expires (&self, id: &str) -> Option<u64> {
+        return fs::read_to_string(self.file_path(id, "expires")?).ok()?.trim().parse().ok();
+    }
+
+    fn write_expires (&self, id: &str, ttl: Duration) -> Result<(), Error> {
+        let path = self.file_path(id, "expires").ok_or_else(invalid_id)?;
+        return self.write_file(&path, (now_secs() + ttl.as_secs()).to_string().as_bytes());
+    }
+
+    /// Temporary file is renamed, so readers never see partially written file
+    fn write_file (&self, path: &Path, content: &[u8]) -> Result<(), Error> {
+        fs::create_dir_all(&self.path)?;
+
+        let mut file = NamedTempFile::new_in(&self.path)?;
+        file.write_all(content)?;
+        file.persist(path).map_err(|err| err.error)?;
+        return Ok(());
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load (&self, id: &str) -> Option<JsonValue> {
+        if !matches!(self.read_expires(id), Some(expires) if expires > now_secs()) {
+            self.remove(id);
+            return None;
+        }
+
+        let content = fs::read_to_string(self.file_path(id, "json")?).ok()?;
+        return json::parse(&content).ok();
+    }
+
+    fn save (&self, id: &str, value: &JsonValue, ttl: Duration) -> Result<(), Error> {
+        let path = self.file_path(id, "json").ok_or_else(invalid_id)?;
+        // Value without expiration time would be removed by concurrent load
+        self.write_expires(id, ttl)?;
+        return self.write_file(&path, value.dump().as_bytes());
+    }
+
+    fn touch (&self, id: &str, ttl: Duration) -> Result<(), Error> {
+        // Removed session isn't brought back
+        if !matches!(self.file_path(id, "json"), Some(path) if path.exists()) { return Ok(()); }
+        return self.write_expires(id, ttl);
+    }
+
+    fn remove (&self, id: &str) {
+        for extension in ["json", "expires"] {
+            if let Some(path) = self.file_path(id, extension) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    fn cleanup (&self) {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(_) => return
+        };
+
+        let now = now_secs();
+        for entry in entries.flatten() {
+            let file_name = entry.file_name();
+            let id = match file_name.to_str().and_then(|name| name.strip_suffix(".expires")) {
+                Some(id) => id,
+                None => continue
+            };
+
+            if !matches!(self.read_expires(id), Some(expires) if expires > now) {
+                self.remove(id);
+            }
+        }
+    }
+}
+
+fn invalid_id () -> Error {
+    return Error::new(ErrorKind::InvalidInput, "Invalid session id");
+}
+
+fn now_secs () -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+}
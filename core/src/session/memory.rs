@@ -0,0 +1,53 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use json::JsonValue;
+use super::SessionStore;
+
+/// Keeps sessions in process memory, they are lost on restart
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, (JsonValue, Instant)>>
+}
+
+impl MemoryStore {
+    pub fn new () -> Self {
+        MemoryStore { sessions: Mutex::new(HashMap::new()) }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default () -> Self { MemoryStore::new() }
+}
+
+impl SessionStore for MemoryStore {
+    fn load (&self, id: &str) -> Option<JsonValue> {
+        let sessions = self.sessions.lock().unwrap();
+        return match sessions.get(id) {
+            Some((value, expires)) if *expires > Instant::now() => Some(value.clone()),
+            _ => None
+        };
+    }
+
+    fn save (&self, id: &str, value: &JsonValue, ttl: Duration) -> Result<(), Error> {
+        self.sessions.lock().unwrap().insert(id.to_string(), (value.clone(), Instant::now() + ttl));
+        return Ok(());
+    }
+
+    fn touch (&self, id: &str, ttl: Duration) -> Result<(), Error> {
+        if let Some((_, expires)) = self.sessions.lock().unwrap().get_mut(id) {
+            *expires = Instant::now() + ttl;
+        }
+
+        return Ok(());
+    }
+
+    fn remove (&self, id: &str) {
+        self.sessions.lock().unwrap().remove(id);
+    }
+
+    fn cleanup (&self) {
+        let now = Instant::now();
+        self.sessions.lock().unwrap().retain(|_, (_, expires)| *expires > now);
+    }
+}
@@ -0,0 +1,248 @@
+use std::any::Any;
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use std::fmt;
+use json::JsonValue;
+use rand::RngCore;
+use crate::app::config::{Config, SessionStoreType};
+use crate::context::http::HttpContext;
+use crate::http::{cookie::{Cookie, SameSite}, entity::Response};
+use crate::utils::log::log_error_lines;
+use self::{file::FileStore, memory::MemoryStore};
+
+pub mod file;
+pub mod memory;
+
+/// Size of random part of session id in bytes
+const ID_SIZE: usize = 32;
+/// Expired sessions are removed from store not more often than this
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Storage of session values, sessions are passed as JSON so stores don't depend on session type
+pub trait SessionStore: Send + Sync {
+    /// Returns stored value, expired sessions are `None`
+    fn load (&self, id: &str) -> Option<JsonValue>;
+    fn save (&self, id: &str, value: &JsonValue, ttl: Duration) -> Result<(), std::io::Error>;
+    /// Extends lifetime of unchanged session
+    fn touch (&self, id: &str, ttl: Duration) -> Result<(), std::io::Error>;
+    fn remove (&self, id: &str);
+    /// Removes expired sessions, called periodically
+    fn cleanup (&self) {}
+}
+
+/// Typed session object, it's converted from and to JSON by store
+pub trait SessionData: Send + Sync + Sized + 'static {
+    /// Session without values, it's used for new sessions and when stored value can't be converted
+    fn empty () -> Self;
+    fn from_json (value: &JsonValue) -> Option<Self>;
+    fn to_json (&self) -> JsonValue;
+}
+
+impl SessionData for JsonValue {
+    fn empty () -> Self { JsonValue::new_object() }
+    fn from_json (value: &JsonValue) -> Option<Self> { Some(value.clone()) }
+    fn to_json (&self) -> JsonValue { self.clone() }
+}
+
+trait AnySession: Send + Sync {
+    fn as_any (&self) -> &dyn Any;
+    fn as_any_mut (&mut self) -> &mut dyn Any;
+    fn to_json (&self) -> JsonValue;
+}
+
+impl<T: SessionData> AnySession for T {
+    fn as_any (&self) -> &dyn Any { self }
+    fn as_any_mut (&mut self) -> &mut dyn Any { self }
+    fn to_json (&self) -> JsonValue { SessionData::to_json(self) }
+}
+
+/// Session layer, loads session before route action and saves it afterwards.
+/// Token is read from `session` header or cookie, new token is sent back in the same way.
+pub struct Sessions {
+    store: Box<dyn SessionStore>,
+    create: fn(Option<&JsonValue>) -> Box<dyn AnySession>,
+    last_cleanup: Mutex<Instant>
+}
+
+impl Sessions {
+    pub fn new<T: SessionData, Store: SessionStore + 'static> (store: Store) -> Self {
+        fn create<T: SessionData> (value: Option<&JsonValue>) -> Box<dyn AnySession> {
+            return Box::new(value.and_then(T::from_json).unwrap_or_else(T::empty));
+        }
+
+        Sessions { store: Box::new(store), create: create::<T>, last_cleanup: Mutex::new(Instant::now()) }
+    }
+
+    /// Store is chosen by `session.store` in config
+    pub fn from_config<T: SessionData> () -> Self {
+        match &Config::get().session.store {
+            SessionStoreType::Memory => Sessions::new::<T, _>(MemoryStore::new()),
+            SessionStoreType::File(path) => Sessions::new::<T, _>(FileStore::new(path.clone()))
+        }
+    }
+
+    /// Loads session and attaches it to context, returned handle is passed to `finish`
+    pub(crate) fn start (&self, ctx: &mut HttpContext) -> Session {
+        let stored = read_token(ctx).and_then(|id| Some((self.store.load(&id)?, id)));
+        let (id, data) = match stored {
+            Some((value, id)) => (Some(id), (self.create)(Some(&value))),
+            None => (None, (self.create)(None))
+        };
+
+        let session = Session(Arc::new(SessionInner {
+            value: Mutex::new(SessionValue { data, is_changed: false }),
+            state: Mutex::new(SessionState { id, is_destroyed: false, is_renewed: false })
+        }));
+        ctx.extensions.insert(session.clone());
+        return session;
+    }
+
+    /// Saves session changed by action, new session token is added to response
+    pub(crate) fn finish (&self, session: Session, res: &mut Response) {
+        let config = &Config::get().session;
+        let value = session.0.value.lock().unwrap();
+        let mut state = session.0.state.lock().unwrap();
+
+        if state.is_destroyed {
+            if let Some(id) = state.id.take() {
+                self.store.remove(&id);
+                set_token(res, "", Duration::ZERO);
+            }
+        } else if value.is_changed || state.is_renewed {
+            if state.is_renewed {
+                if let Some(id) = state.id.take() { self.store.remove(&id); }
+            }
+
+            let id = state.id.get_or_insert_with(generate_id).clone();
+            match self.store.save(&id, &value.data.to_json(), config.ttl) {
+                Ok(()) => set_token(res, &id, config.ttl),
+                Err(err) => log_error_lines("Session saving error", err.to_string())
+            }
+        } else if let Some(id) = &state.id {
+            // Cookie expires as late as stored session, so it's sent again with new lifetime
+            match self.store.touch(id, config.ttl) {
+                Ok(()) => set_token(res, id, config.ttl),
+                Err(err) => log_error_lines("Session saving error", err.to_string())
+            }
+        }
+
+        drop((value, state));
+        self.cleanup();
+    }
+
+    fn cleanup (&self) {
+        let mut last_cleanup = self.last_cleanup.lock().unwrap();
+        if last_cleanup.elapsed() < CLEANUP_INTERVAL { return; }
+
+        *last_cleanup = Instant::now();
+        drop(last_cleanup);
+        self.store.cleanup();
+    }
+}
+
+/// Handle of request session, it's stored in context extensions
+#[derive(Clone)]
+pub struct Session(Arc<SessionInner>);
+
+/// Object and token are locked separately, so session can be destroyed while object is borrowed
+struct SessionInner {
+    value: Mutex<SessionValue>,
+    state: Mutex<SessionState>
+}
+
+struct SessionValue {
+    data: Box<dyn AnySession>,
+    is_changed: bool
+}
+
+struct SessionState {
+    /// `None` until new session is saved
+    id: Option<String>,
+    is_destroyed: bool,
+    is_renewed: bool
+}
+
+impl Session {
+    /// Returns session object, `None` if sessions are used with other type.
+    /// Object stays locked while returned reference is alive, so it's `None` if object is borrowed already.
+    pub fn get<T: SessionData> (&self) -> Option<SessionRef<'_, T>> {
+        let guard = self.0.value.try_lock().ok()?;
+        if !guard.data.as_any().is::<T>() { return None; }
+
+        return Some(SessionRef { guard, marker: PhantomData });
+    }
+
+    /// Token of stored session, `None` for new session
+    pub fn id (&self) -> Option<String> {
+        return self.0.state.lock().unwrap().id.clone();
+    }
+
+    /// Removes session from store after action
+    pub fn destroy (&self) {
+        self.0.state.lock().unwrap().is_destroyed = true;
+    }
+
+    /// Moves session to new token, should be called when user logs in
+    pub fn renew (&self) {
+        self.0.state.lock().unwrap().is_renewed = true;
+    }
+}
+
+impl fmt::Debug for Session {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Session({:?})", self.id())
+    }
+}
+
+/// Locked session object, mutable access marks session to be saved
+pub struct SessionRef<'a, T: SessionData> {
+    guard: MutexGuard<'a, SessionValue>,
+    marker: PhantomData<T>
+}
+
+impl<T: SessionData> Deref for SessionRef<'_, T> {
+    type Target = T;
+
+    fn deref (&self) -> &T {
+        return self.guard.data.as_any().downcast_ref().unwrap();
+    }
+}
+
+impl<T: SessionData> DerefMut for SessionRef<'_, T> {
+    fn deref_mut (&mut self) -> &mut T {
+        self.guard.is_changed = true;
+        return self.guard.data.as_any_mut().downcast_mut().unwrap();
+    }
+}
+
+/// Token from `session` header has priority over cookie
+fn read_token (ctx: &HttpContext) -> Option<String> {
+    let token = ctx.req.headers.get("session").or_else(|| {
+        let name = Config::get().session.cookie.as_ref()?;
+        return ctx.cookies.get(name).map(str::to_string);
+    })?;
+
+    // Anything else can't be issued by server, store doesn't see it
+    return Some(token).filter(|token| is_valid_id(token));
+}
+
+fn set_token (res: &mut Response, id: &str, ttl: Duration) {
+    res.headers.set("session".to_string(), id.to_string());
+    if let Some(name) = &Config::get().session.cookie {
+        let cookie = Cookie::new(name, id).path("/").max_age(ttl).http_only(true).same_site(SameSite::Lax);
+        res.headers.append("set-cookie".to_string(), cookie.to_string());
+    }
+}
+
+fn generate_id () -> String {
+    let mut bytes = [0u8; ID_SIZE];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    return base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+}
+
+/// Ids are URL-safe base64 without padding, so they are also safe as file names
+pub(crate) fn is_valid_id (id: &str) -> bool {
+    return id.len() == (ID_SIZE * 4).div_ceil(3) && id.bytes().all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_');
+}
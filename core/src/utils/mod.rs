@@ -56,3 +56,39 @@ where
         _ => None,
     }
 }
+
+/// Decodes `%XX` sequences and `+` as space, malformed sequences are kept as is
+pub fn url_decode (value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+
+    let mut i = 0usize;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => result.push(b' '),
+            b'%' if i + 2 < bytes.len() && is_hex_pair(bytes[i + 1], bytes[i + 2]) => {
+                result.push(hex_value(bytes[i + 1]) << 4 | hex_value(bytes[i + 2]));
+                i += 2;
+            }
+            byte => result.push(byte)
+        }
+
+        i += 1;
+    }
+
+    return String::from_utf8_lossy(&result).into_owned();
+}
+
+#[inline]
+fn is_hex_pair (high: u8, low: u8) -> bool {
+    return high.is_ascii_hexdigit() && low.is_ascii_hexdigit();
+}
+
+#[inline]
+fn hex_value (digit: u8) -> u8 {
+    match digit {
+        b'0'..=b'9' => digit - b'0',
+        b'a'..=b'f' => digit - b'a' + 10,
+        _ => digit - b'A' + 10
+    }
+}
@@ -1,12 +1,11 @@
-use std::{net::TcpStream, io::BufRead};
-use bufstream::BufStream;
+use std::io::BufRead;
 
 pub trait StreamUtils {
     fn read_before (&mut self, needle: u8, cb: &mut Vec<u8>) -> Option<usize>;
     fn read_string_before (&mut self, needle: char) -> Option<String>;
 }
 
-impl StreamUtils for BufStream<TcpStream> {
+impl<T: BufRead> StreamUtils for T {
     #[inline]
     fn read_before (&mut self, needle: u8, buffer: &mut Vec<u8>) -> Option<usize> {
         let offset = buffer.len();
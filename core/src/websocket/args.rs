@@ -0,0 +1,173 @@
+use std::fmt;
+use json::{JsonValue, object};
+
+/// Arguments of incoming event, sent by client as JSON array: `event:[1, "two", {"three": 3}]`
+#[derive(Debug)]
+pub struct SocketArgs(Vec<JsonValue>);
+
+impl SocketArgs {
+    pub fn empty () -> Self {
+        SocketArgs(Vec::new())
+    }
+
+    /// Parses payload of `event:payload` message, empty payload means no arguments
+    pub fn parse (payload: &str) -> Result<Self, SocketError> {
+        if payload.trim().is_empty() { return Ok(SocketArgs::empty()); }
+
+        match json::parse(payload) {
+            Ok(JsonValue::Array(values)) => Ok(SocketArgs(values)),
+            Ok(_) => Err(SocketError::new("invalid_payload", "Arguments must be JSON array")),
+            Err(err) => Err(SocketError::new("invalid_payload", &err.to_string()))
+        }
+    }
+
+    #[inline]
+    pub fn len (&self) -> usize {
+        return self.0.len();
+    }
+
+    #[inline]
+    pub fn is_empty (&self) -> bool {
+        return self.0.is_empty();
+    }
+
+    /// Returns argument as is, missing argument is `null`
+    pub fn raw (&self, index: usize) -> &JsonValue {
+        return self.0.get(index).unwrap_or(&JsonValue::Null);
+    }
+
+    /// Converts argument, missing and `null` arguments are `None`
+    pub fn get<T: FromArg> (&self, index: usize) -> Result<Option<T>, SocketError> {
+        let value = self.raw(index);
+        if value.is_null() { return Ok(None); }
+
+        return match T::from_arg(value) {
+            Some(value) => Ok(Some(value)),
+            None => Err(SocketError::new("invalid_argument", &format!("Argument {} has wrong type", index)))
+        };
+    }
+
+    /// Same as `get`, but missing argument is also treated as error
+    pub fn require<T: FromArg> (&self, index: usize) -> Result<T, SocketError> {
+        return self.get(index)?.ok_or_else(|| SocketError::new("invalid_argument", &format!("Argument {} is required", index)));
+    }
+
+    #[inline]
+    pub fn into_vec (self) -> Vec<JsonValue> {
+        return self.0;
+    }
+}
+
+/// Conversion of JSON argument into Rust type
+pub trait FromArg: Sized {
+    fn from_arg (value: &JsonValue) -> Option<Self>;
+}
+
+impl FromArg for JsonValue {
+    fn from_arg (value: &JsonValue) -> Option<Self> { Some(value.clone()) }
+}
+
+impl FromArg for String {
+    fn from_arg (value: &JsonValue) -> Option<Self> { value.as_str().map(str::to_string) }
+}
+
+impl FromArg for bool {
+    fn from_arg (value: &JsonValue) -> Option<Self> { value.as_bool() }
+}
+
+macro_rules! impl_number_arg {
+    ($($target:ty => $method:ident),*) => {
+        $(impl FromArg for $target {
+            fn from_arg (value: &JsonValue) -> Option<Self> { value.$method() }
+        })*
+    };
+}
+
+impl_number_arg!(
+    i8 => as_i8, i16 => as_i16, i32 => as_i32, i64 => as_i64, isize => as_isize,
+    u8 => as_u8, u16 => as_u16, u32 => as_u32, u64 => as_u64, usize => as_usize,
+    f32 => as_f32, f64 => as_f64
+);
+
+impl<T: FromArg> FromArg for Option<T> {
+    fn from_arg (value: &JsonValue) -> Option<Self> {
+        if value.is_null() { return Some(None); }
+        return T::from_arg(value).map(Some);
+    }
+}
+
+impl<T: FromArg> FromArg for Vec<T> {
+    fn from_arg (value: &JsonValue) -> Option<Self> {
+        if !value.is_array() { return None; }
+        return value.members().map(T::from_arg).collect();
+    }
+}
+
+/// Value returned by event handler, it's sent back if client requested acknowledgement
+pub trait IntoReply {
+    fn into_reply (self) -> Result<JsonValue, SocketError>;
+}
+
+impl IntoReply for () {
+    fn into_reply (self) -> Result<JsonValue, SocketError> { Ok(JsonValue::Null) }
+}
+
+impl IntoReply for JsonValue {
+    fn into_reply (self) -> Result<JsonValue, SocketError> { Ok(self) }
+}
+
+impl<T: IntoReply> IntoReply for Result<T, SocketError> {
+    fn into_reply (self) -> Result<JsonValue, SocketError> { self.and_then(T::into_reply) }
+}
+
+macro_rules! impl_value_reply {
+    ($($target:ty),*) => {
+        $(impl IntoReply for $target {
+            fn into_reply (self) -> Result<JsonValue, SocketError> { Ok(self.into()) }
+        })*
+    };
+}
+
+impl_value_reply!(String, &str, bool, i32, i64, u32, u64, usize, f32, f64);
+
+/// Error reported to client by `error` event: `error:[{"code": "...", "message": "..."}]`
+#[derive(Debug)]
+pub struct SocketError {
+    pub code: String,
+    pub message: String,
+    /// Name of the event that caused error
+    pub event: Option<String>
+}
+
+impl SocketError {
+    pub fn new (code: &str, message: &str) -> Self {
+        SocketError { code: code.to_string(), message: message.to_string(), event: None }
+    }
+
+    pub fn with_event (mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        return self;
+    }
+
+    pub fn to_json (&self) -> JsonValue {
+        let mut value = object! { code: self.code.as_str(), message: self.message.as_str() };
+        if let Some(event) = &self.event {
+            value["event"] = event.as_str().into();
+        }
+
+        return value;
+    }
+}
+
+/// Failed send in handler is reported as `send_failed`, so `?` can be used on send methods
+impl From<std::io::Error> for SocketError {
+    fn from (err: std::io::Error) -> Self {
+        SocketError::new("send_failed", &err.to_string())
+    }
+}
+
+impl fmt::Display for SocketError {
+    fn fmt (&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
@@ -0,0 +1,158 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tungstenite::Message;
+use super::sender::{SocketSender, PreparedMessage, format_event};
+
+/// Registry of open sockets and named channels they are subscribed to.
+/// Sockets are removed automatically when they are closed.
+pub struct Channels {
+    inner: Mutex<ChannelsInner>
+}
+
+struct ChannelsInner {
+    next_id: usize,
+    sockets: HashMap<usize, SocketEntry>,
+    channels: HashMap<String, HashSet<usize>>
+}
+
+struct SocketEntry {
+    /// Path of the endpoint socket is connected to
+    endpoint: String,
+    sender: SocketSender,
+    channels: HashSet<String>
+}
+
+impl Channels {
+    pub fn new () -> Self {
+        Channels {
+            inner: Mutex::new(ChannelsInner {
+                next_id: 0,
+                sockets: HashMap::new(),
+                channels: HashMap::new()
+            })
+        }
+    }
+
+    /// Returns id of the registered socket
+    pub(crate) fn add (&self, endpoint: &str, sender: SocketSender) -> usize {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        inner.sockets.insert(id, SocketEntry { endpoint: endpoint.to_string(), sender, channels: HashSet::new() });
+        return id;
+    }
+
+    pub(crate) fn remove (&self, id: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.sockets.remove(&id) {
+            for channel in entry.channels {
+                inner.leave(&channel, id);
+            }
+        }
+    }
+
+    pub(crate) fn subscribe (&self, id: usize, channel: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.sockets.get_mut(&id) {
+            entry.channels.insert(channel.to_string());
+            inner.channels.entry(channel.to_string()).or_default().insert(id);
+        }
+    }
+
+    pub(crate) fn unsubscribe (&self, id: usize, channel: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(entry) = inner.sockets.get_mut(&id) {
+            entry.channels.remove(channel);
+            inner.leave(channel, id);
+        }
+    }
+
+    pub(crate) fn is_subscribed (&self, id: usize, channel: &str) -> bool {
+        let inner = self.inner.lock().unwrap();
+        return matches!(inner.sockets.get(&id), Some(entry) if entry.channels.contains(channel));
+    }
+
+    /// Count of sockets subscribed to `channel`
+    pub fn count (&self, channel: &str) -> usize {
+        let inner = self.inner.lock().unwrap();
+        return inner.channels.get(channel).map_or(0, HashSet::len);
+    }
+
+    /// Sends event to every socket subscribed to `channel`
+    #[inline]
+    pub fn broadcast (&self, channel: &str, event: &str, message: &str) {
+        self.broadcast_except(channel, None, event, message);
+    }
+
+    /// Sends event to every socket connected to endpoint with `path`
+    #[inline]
+    pub fn broadcast_endpoint (&self, path: &str, event: &str, message: &str) {
+        self.broadcast_endpoint_except(path, None, event, message);
+    }
+
+    /// Starts closing handshake of every open socket
+    pub(crate) fn close_all (&self, code: u16, reason: &str) {
+        let senders: Vec<SocketSender> = {
+            let inner = self.inner.lock().unwrap();
+            inner.sockets.values().map(|entry| entry.sender.clone()).collect()
+        };
+
+        for sender in senders {
+            sender.close(code, reason);
+        }
+    }
+
+    pub(crate) fn broadcast_except (&self, channel: &str, except: Option<usize>, event: &str, message: &str) {
+        let senders = {
+            let inner = self.inner.lock().unwrap();
+            match inner.channels.get(channel) {
+                Some(ids) => ids.iter()
+                    .filter(|id| Some(**id) != except)
+                    .filter_map(|id| inner.sockets.get(id))
+                    .map(|entry| entry.sender.clone())
+                    .collect(),
+                None => Vec::new()
+            }
+        };
+
+        send_all(senders, event, message);
+    }
+
+    pub(crate) fn broadcast_endpoint_except (&self, path: &str, except: Option<usize>, event: &str, message: &str) {
+        let senders = {
+            let inner = self.inner.lock().unwrap();
+            inner.sockets.iter()
+                .filter(|(id, entry)| Some(**id) != except && entry.endpoint == path)
+                .map(|(_, entry)| entry.sender.clone())
+                .collect()
+        };
+
+        send_all(senders, event, message);
+    }
+}
+
+impl Default for Channels {
+    fn default () -> Self { Channels::new() }
+}
+
+impl ChannelsInner {
+    fn leave (&mut self, channel: &str, id: usize) {
+        if let Some(ids) = self.channels.get_mut(channel) {
+            ids.remove(&id);
+            if ids.is_empty() { self.channels.remove(channel); }
+        }
+    }
+}
+
+/// Frames are only queued, writers of sockets send them, so broadcast never waits for slow clients.
+/// Client that falls behind by more than `sendQueue` bytes is disconnected.
+fn send_all (senders: Vec<SocketSender>, event: &str, message: &str) {
+    if senders.is_empty() { return; }
+
+    if let Ok(msg) = PreparedMessage::new(Message::text(format_event(event, message)), &senders) {
+        for sender in senders {
+            // Closed and disconnected sockets are removed by their own loops
+            let _ = sender.send_prepared(&msg);
+        }
+    }
+}
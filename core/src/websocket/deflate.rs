@@ -0,0 +1,325 @@
+use std::io::{Cursor, Error, ErrorKind, Read};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use tungstenite::protocol::frame::{Frame, FrameHeader, coding::{OpCode, Data}};
+use crate::app::config::DeflateConfig;
+use super::sender::into_io_error;
+
+/// Same as default message size limit of tungstenite
+const MAX_MESSAGE_SIZE: usize = 64 << 20;
+/// Empty block that ends every compressed message, it isn't sent by the extension
+const DEFLATE_TAIL: [u8; 4] = [0, 0, 0xff, 0xff];
+
+/// Accepts first `permessage-deflate` offer that can be satisfied, returns value of `Sec-WebSocket-Extensions` response header.
+/// Server always compresses messages independently, so one compressed frame can be broadcasted to many sockets.
+pub(crate) fn negotiate (offers: &str, config: &DeflateConfig) -> Option<String> {
+    for offer in offers.split(',') {
+        let mut params = offer.split(';').map(str::trim);
+        if params.next() != Some("permessage-deflate") { continue; }
+
+        if let Some(response) = accept_offer(params, config) {
+            return Some(response);
+        }
+    }
+
+    return None;
+}
+
+fn accept_offer<'a> (params: impl Iterator<Item = &'a str>, config: &DeflateConfig) -> Option<String> {
+    let mut response = "permessage-deflate; server_no_context_takeover".to_string();
+    let mut seen = Vec::new();
+
+    for param in params {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+            None => (param, None)
+        };
+
+        // Offer with duplicated or unknown parameter must be declined
+        if seen.contains(&name) { return None; }
+        seen.push(name);
+
+        match (name, value) {
+            ("server_no_context_takeover", None) | ("client_no_context_takeover", None) => {},
+            ("server_max_window_bits", Some(bits)) => {
+                // Compressor can't be restricted per socket, it would break shared broadcast frames
+                if parse_window_bits(bits)? < config.window_bits { return None; }
+            },
+            ("client_max_window_bits", bits) => {
+                let bits = match bits {
+                    Some(bits) => parse_window_bits(bits)?,
+                    None => 15
+                };
+
+                let bits = bits.min(config.window_bits);
+                if bits < 15 { response.push_str(&format!("; client_max_window_bits={}", bits)); }
+            },
+            _ => return None
+        }
+    }
+
+    if config.window_bits < 15 {
+        response.push_str(&format!("; server_max_window_bits={}", config.window_bits));
+    }
+
+    return Some(response);
+}
+
+fn parse_window_bits (value: &str) -> Option<u8> {
+    return value.parse::<u8>().ok().filter(|bits| (8..=15).contains(bits));
+}
+
+/// Compresses payload of one message without context takeover
+pub(crate) fn compress (data: &[u8], window_bits: u8) -> Result<Vec<u8>, Error> {
+    let mut compress = Compress::new_with_window_bits(Compression::default(), false, window_bits);
+    let mut output = Vec::with_capacity(data.len() / 2 + 64);
+
+    loop {
+        let consumed = compress.total_in() as usize;
+        compress.compress_vec(&data[consumed..], &mut output, FlushCompress::Sync).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        // Flush is complete when output buffer wasn't filled
+        if compress.total_in() as usize == data.len() && output.len() < output.capacity() { break; }
+        output.reserve(output.capacity());
+    }
+
+    if output.ends_with(&DEFLATE_TAIL) { output.truncate(output.len() - DEFLATE_TAIL.len()); }
+    if output.is_empty() { output.push(0); }
+    return Ok(output);
+}
+
+/// Rewrites compressed frames from client into plain ones before they reach tungstenite,
+/// control and uncompressed frames are passed as is
+pub(crate) struct Inflater {
+    decompress: Decompress,
+    /// Bytes from socket that don't make complete frame yet
+    input: Vec<u8>,
+    /// Frames ready to be read
+    output: Vec<u8>,
+    output_pos: usize,
+    /// Compressed message which final fragment isn't received yet
+    message: Option<(Data, Vec<u8>)>,
+    /// Limit of frame and inflated message size
+    max_size: usize
+}
+
+impl Inflater {
+    /// `buffered` are bytes that were read from socket ahead of handshake completion
+    pub fn new (buffered: Vec<u8>) -> Self {
+        Inflater {
+            decompress: Decompress::new(false),
+            input: buffered,
+            output: Vec::new(),
+            output_pos: 0,
+            message: None,
+            max_size: MAX_MESSAGE_SIZE
+        }
+    }
+
+    pub fn read<R: Read> (&mut self, reader: &mut R, buf: &mut [u8]) -> Result<usize, Error> {
+        while self.output_pos == self.output.len() {
+            self.output.clear();
+            self.output_pos = 0;
+
+            if !self.process_frames()? {
+                let mut chunk = [0u8; 8192];
+                let read = reader.read(&mut chunk)?;
+                if read == 0 { return Ok(0); }
+                self.input.extend_from_slice(&chunk[..read]);
+            }
+        }
+
+        let size = buf.len().min(self.output.len() - self.output_pos);
+        buf[..size].copy_from_slice(&self.output[self.output_pos..self.output_pos + size]);
+        self.output_pos += size;
+        return Ok(size);
+    }
+
+    /// Moves complete frames from input, returns `false` if there was no complete frame
+    fn process_frames (&mut self) -> Result<bool, Error> {
+        let mut is_processed = false;
+        loop {
+            let (header, header_size, length) = {
+                let mut cursor = Cursor::new(&self.input);
+                match FrameHeader::parse(&mut cursor).map_err(into_io_error)? {
+                    Some((header, length)) => (header, cursor.position() as usize, length),
+                    None => break
+                }
+            };
+
+            if length > self.max_size as u64 { return Err(Error::new(ErrorKind::InvalidData, "Frame is too big")); }
+            let frame_size = header_size + length as usize;
+            if self.input.len() < frame_size { break; }
+
+            let frame: Vec<u8> = self.input.drain(..frame_size).collect();
+            self.process_frame(header, frame, header_size)?;
+            is_processed = true;
+        }
+
+        return Ok(is_processed);
+    }
+
+    fn process_frame (&mut self, header: FrameHeader, frame: Vec<u8>, header_size: usize) -> Result<(), Error> {
+        match header.opcode {
+            OpCode::Data(kind @ (Data::Text | Data::Binary)) if header.rsv1 => {
+                let payload = unmask(&frame[header_size..], header.mask);
+                if header.is_final {
+                    self.push_message(kind, payload)?;
+                } else {
+                    self.message = Some((kind, payload));
+                }
+            },
+            OpCode::Data(Data::Continue) if self.message.is_some() => {
+                let (kind, mut payload) = self.message.take().unwrap();
+                payload.extend_from_slice(&unmask(&frame[header_size..], header.mask));
+                if payload.len() > self.max_size { return Err(Error::new(ErrorKind::InvalidData, "Message is too big")); }
+
+                if header.is_final {
+                    self.push_message(kind, payload)?;
+                } else {
+                    self.message = Some((kind, payload));
+                }
+            },
+            _ => self.output.extend_from_slice(&frame)
+        }
+
+        return Ok(());
+    }
+
+    fn push_message (&mut self, kind: Data, payload: Vec<u8>) -> Result<(), Error> {
+        let mut frame = Frame::message(self.inflate(payload)?, OpCode::Data(kind), true);
+        // Tungstenite requires masked frames from client, zero mask keeps payload as is
+        frame.header_mut().mask = Some([0; 4]);
+        return frame.format(&mut self.output).map_err(into_io_error);
+    }
+
+    fn inflate (&mut self, mut payload: Vec<u8>) -> Result<Vec<u8>, Error> {
+        payload.extend_from_slice(&DEFLATE_TAIL);
+        let start = self.decompress.total_in();
+        let mut output = Vec::with_capacity(payload.len() * 2);
+
+        loop {
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let produced = output.len();
+            self.decompress.decompress_vec(&payload[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            if output.len() > self.max_size { return Err(Error::new(ErrorKind::InvalidData, "Message is too big")); }
+
+            let consumed_now = (self.decompress.total_in() - start) as usize;
+            if consumed_now == payload.len() && output.len() < output.capacity() { break; }
+            // No progress while output has free space means that input is broken
+            if consumed_now == consumed && output.len() == produced && output.len() < output.capacity() {
+                return Err(Error::new(ErrorKind::InvalidData, "Malformed compressed message"));
+            }
+
+            output.reserve(output.capacity());
+        }
+
+        return Ok(output);
+    }
+}
+
+fn unmask (payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    match mask {
+        Some(mask) => payload.iter().enumerate().map(|(index, byte)| byte ^ mask[index & 3]).collect(),
+        None => payload.to_vec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Error, Read, Write};
+    use tungstenite::{Message, WebSocket, protocol::{Role, frame::{Frame, coding::{Data, OpCode}}}};
+    use crate::app::config::DeflateConfig;
+    use super::{Inflater, compress, negotiate};
+
+    /// Client socket that delivers few bytes per read, replies of server are discarded
+    struct Stream {
+        input: Trickle,
+        inflater: Inflater
+    }
+
+    struct Trickle(Cursor<Vec<u8>>);
+
+    impl Read for Trickle {
+        fn read (&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            let len = buf.len().min(5);
+            return self.0.read(&mut buf[..len]);
+        }
+    }
+
+    impl Read for Stream {
+        fn read (&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+            return self.inflater.read(&mut self.input, buf);
+        }
+    }
+
+    impl Write for Stream {
+        fn write (&mut self, buf: &[u8]) -> Result<usize, Error> { Ok(buf.len()) }
+        fn flush (&mut self) -> Result<(), Error> { Ok(()) }
+    }
+
+    fn socket (input: Vec<u8>, max_size: usize) -> WebSocket<Stream> {
+        let mut inflater = Inflater::new(Vec::new());
+        inflater.max_size = max_size;
+        return WebSocket::from_raw_socket(Stream { input: Trickle(Cursor::new(input)), inflater }, Role::Server, None);
+    }
+
+    fn client_frame (mut frame: Frame, is_compressed: bool) -> Vec<u8> {
+        frame.header_mut().rsv1 = is_compressed;
+        frame.header_mut().mask = Some([0x12, 0x34, 0x56, 0x78]);
+
+        let mut output = Vec::new();
+        frame.format(&mut output).unwrap();
+        return output;
+    }
+
+    #[test]
+    fn fragmented_message_round_trip () {
+        let text = "Hello compressed world! ".repeat(200);
+        let compressed = compress(text.as_bytes(), 15).unwrap();
+        let third = compressed.len() / 3;
+
+        // Only the first fragment is marked as compressed, control frames may come between fragments
+        let mut input = client_frame(Frame::message(compressed[..third].to_vec(), OpCode::Data(Data::Text), false), true);
+        input.extend(client_frame(Frame::ping(b"ping".to_vec()), false));
+        input.extend(client_frame(Frame::message(compressed[third..(2 * third)].to_vec(), OpCode::Data(Data::Continue), false), false));
+        input.extend(client_frame(Frame::message(compressed[(2 * third)..].to_vec(), OpCode::Data(Data::Continue), true), false));
+        input.extend(client_frame(Frame::message(b"plain".to_vec(), OpCode::Data(Data::Binary), true), false));
+
+        let mut socket = socket(input, 1 << 20);
+        assert_eq!(socket.read_message().unwrap(), Message::Ping(b"ping".to_vec()));
+        assert_eq!(socket.read_message().unwrap(), Message::Text(text));
+        assert_eq!(socket.read_message().unwrap(), Message::Binary(b"plain".to_vec()));
+    }
+
+    #[test]
+    fn server_no_context_takeover_is_negotiated () {
+        let config = DeflateConfig { window_bits: 15, threshold: 0 };
+        let accepted = Some("permessage-deflate; server_no_context_takeover".to_string());
+        assert_eq!(negotiate("permessage-deflate", &config), accepted);
+        assert_eq!(negotiate("permessage-deflate; server_no_context_takeover; client_no_context_takeover", &config), accepted);
+        assert_eq!(negotiate("permessage-deflate; client_max_window_bits", &config), accepted);
+        assert_eq!(negotiate("permessage-deflate; server_no_context_takeover=1", &config), None);
+        assert_eq!(negotiate("x-webkit-deflate-frame", &config), None);
+
+        // Offer with duplicated parameter is declined, the next one is accepted
+        let offers = "permessage-deflate; server_no_context_takeover; server_no_context_takeover, permessage-deflate; client_max_window_bits=10";
+        assert_eq!(negotiate(offers, &config).as_deref(), Some("permessage-deflate; server_no_context_takeover; client_max_window_bits=10"));
+
+        let config = DeflateConfig { window_bits: 10, threshold: 0 };
+        assert_eq!(negotiate("permessage-deflate; server_max_window_bits=12", &config).as_deref(), Some("permessage-deflate; server_no_context_takeover; server_max_window_bits=10"));
+        assert_eq!(negotiate("permessage-deflate; server_max_window_bits=9", &config), None);
+    }
+
+    #[test]
+    fn inflated_message_over_limit_is_rejected () {
+        let data = vec![0u8; 64 * 1024];
+        let compressed = compress(&data, 15).unwrap();
+        assert!(compressed.len() < 1024);
+
+        let mut socket = self::socket(client_frame(Frame::message(compressed.clone(), OpCode::Data(Data::Binary), true), true), 16 * 1024);
+        assert!(socket.read_message().is_err());
+
+        let mut socket = self::socket(client_frame(Frame::message(compressed.clone(), OpCode::Data(Data::Binary), true), true), 64 * 1024);
+        assert_eq!(socket.read_message().unwrap(), Message::Binary(data));
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::{Duration, Instant};
+use crate::app::config::WebSocketConfig;
+
+/// What has to be done with socket before the next read
+pub(crate) enum HeartbeatAction {
+    Wait,
+    Ping,
+    /// Idle timeout passed, socket should be closed
+    Idle,
+    /// Ping or closing handshake wasn't answered in time
+    Lost
+}
+
+/// Tracks ping, idle and closing deadlines of one socket
+pub(crate) struct Heartbeat<'a> {
+    config: &'a WebSocketConfig,
+    /// Any frame received, including pongs
+    last_seen: Instant,
+    /// Text or binary message received
+    last_message: Instant,
+    ping_sent: Option<Instant>,
+    closing_since: Option<Instant>
+}
+
+impl<'a> Heartbeat<'a> {
+    pub fn new (config: &'a WebSocketConfig) -> Self {
+        let now = Instant::now();
+        Heartbeat { config, last_seen: now, last_message: now, ping_sent: None, closing_since: None }
+    }
+
+    pub fn seen (&mut self, is_message: bool) {
+        self.last_seen = Instant::now();
+        self.ping_sent = None;
+        if is_message { self.last_message = self.last_seen; }
+    }
+
+    /// Close frame was sent, client has `pong_timeout` to confirm it
+    pub fn closing (&mut self) {
+        if self.closing_since.is_none() {
+            self.closing_since = Some(Instant::now());
+        }
+    }
+
+    #[inline]
+    pub fn is_closing (&self) -> bool {
+        return self.closing_since.is_some();
+    }
+
+    pub fn check (&mut self) -> HeartbeatAction {
+        let now = Instant::now();
+        if let Some(since) = self.closing_since {
+            if now >= since + self.config.pong_timeout { return HeartbeatAction::Lost; }
+            return HeartbeatAction::Wait;
+        }
+
+        if let Some(sent) = self.ping_sent {
+            if now >= sent + self.config.pong_timeout { return HeartbeatAction::Lost; }
+        }
+
+        if let Some(idle_timeout) = self.config.idle_timeout {
+            if now >= self.last_message + idle_timeout { return HeartbeatAction::Idle; }
+        }
+
+        if let (None, Some(interval)) = (self.ping_sent, self.config.ping_interval) {
+            if now >= self.last_seen + interval {
+                self.ping_sent = Some(now);
+                return HeartbeatAction::Ping;
+            }
+        }
+
+        return HeartbeatAction::Wait;
+    }
+
+    /// Read timeout until the nearest deadline, `None` if socket can wait for frames forever
+    pub fn timeout (&self) -> Option<Duration> {
+        let pong_timeout = self.config.pong_timeout;
+        let deadline = match self.closing_since {
+            Some(since) => since + pong_timeout,
+            None => [
+                self.ping_sent.map(|sent| sent + pong_timeout),
+                self.config.idle_timeout.map(|idle_timeout| self.last_message + idle_timeout),
+                self.config.ping_interval.filter(|_| self.ping_sent.is_none()).map(|interval| self.last_seen + interval)
+            ].into_iter().flatten().min()?
+        };
+
+        // Zero read timeout isn't allowed
+        return Some(deadline.saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,503 @@
+use std::{collections::HashMap, io::{self, ErrorKind}, mem, sync::Arc};
+use sha1::{Sha1, Digest};
+use tungstenite::{Message, Error, protocol::frame::coding::CloseCode};
+use crate::{http::{cors::CorsConfig, entity::{Response, HttpHeaders, ResponseType, Request}, codes::HttpCode, urlencoded::UrlEncoded}, app::{App, config::Config, router::PathMatcher}, context::{ws::SocketContext, extensions::Extensions}, utils::log::log_error_lines};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+#[cfg(feature = "async")]
+use tokio::task::block_in_place;
+use json::JsonValue;
+use self::args::{IntoReply, SocketArgs, SocketError};
+use self::heartbeat::{Heartbeat, HeartbeatAction};
+use self::sender::into_io_error;
+
+pub mod args;
+pub mod channels;
+pub(crate) mod deflate;
+mod heartbeat;
+mod outbox;
+pub mod sender;
+
+type EventCallerType = dyn Fn(&mut SocketContext, SocketArgs) -> Result<JsonValue, SocketError> + Sync + Send + 'static;
+type OpenCallerType = dyn Fn(&mut SocketContext) + Sync + Send + 'static;
+/* ctx, close code, reason */
+type CloseCallerType = dyn Fn(&mut SocketContext, u16, &str) + Sync + Send + 'static;
+type ErrorCallerType = dyn Fn(&mut SocketContext, &Error) + Sync + Send + 'static;
+type HandshakeCallerType = dyn Fn(&Request, &UrlEncoded, &mut Handshake) -> Result<(), HttpCode> + Sync + Send + 'static;
+type BinaryCallerType = dyn Fn(&mut SocketContext, Vec<u8>) -> Result<(), SocketError> + Sync + Send + 'static;
+
+pub enum HandshakeResult {
+    Ok(Handshake, Response),
+    Err(Response)
+}
+
+impl HandshakeResult {
+    pub fn err (code: HttpCode, message: &str) -> Self {
+        HandshakeResult::Err(Response::from_code(code, message))
+    }
+
+    pub fn ok (handshake: Handshake, res_headers: HttpHeaders) -> Self {
+        HandshakeResult::Ok(
+            handshake,
+            Response {
+                code: HttpCode::SwitchingProtocols,
+                headers: res_headers,
+                payload: ResponseType::Upgrade
+            }
+        )
+    }
+}
+
+/// Accepted handshake, passed to `handshake` handler of the endpoint and then moved into `SocketContext`
+pub struct Handshake {
+    pub endpoint: usize,
+    pub params: HashMap<String, String>,
+    /// Subprotocols offered by client in `Sec-WebSocket-Protocol`
+    pub protocols: Vec<String>,
+    protocol: Option<String>,
+    /// Values moved to `ctx.extensions()` when socket is opened
+    pub extensions: Extensions,
+    /// `permessage-deflate` was negotiated
+    pub(crate) is_deflate: bool
+}
+
+impl Handshake {
+    fn new (endpoint: usize, params: HashMap<String, String>, req: &Request) -> Self {
+        let protocols = req.headers.get_list("sec-websocket-protocol").map(str::to_string).collect();
+
+        Handshake { endpoint, params, protocols, protocol: None, extensions: Extensions::new(), is_deflate: false }
+    }
+
+    /// Selects subprotocol, returns `false` if client didn't offer it
+    pub fn select_protocol (&mut self, name: &str) -> bool {
+        if !self.protocols.iter().any(|offered| offered == name) { return false; }
+
+        self.protocol = Some(name.to_string());
+        return true;
+    }
+
+    #[inline]
+    pub fn protocol (&self) -> Option<&str> {
+        return self.protocol.as_deref();
+    }
+
+    pub(crate) fn take_params (&mut self) -> HashMap<String, String> {
+        return mem::take(&mut self.params);
+    }
+
+    pub(crate) fn into_session (self) -> (Option<String>, Extensions) {
+        return (self.protocol, self.extensions);
+    }
+}
+
+pub fn websocket_handshake (app: &App, req: &Request) -> HandshakeResult {
+    let (endpoint, params) = match app.ws_endpoints.match_path(&req.path) {
+        Some(value) => value,
+        None => return HandshakeResult::err(HttpCode::NotFound, "API endpoint not found")
+    };
+
+    let mut res_headers = HttpHeaders::empty();
+    res_headers.set("connection".to_string(), "Upgrade".to_string());
+    res_headers.set("upgrade".to_string(), "websocket".to_string());
+
+    if matches!(req.headers.get("sec-websocket-version"), Some(version) if version == "13") {
+        res_headers.set("sec-websocket-version".to_string(), "13".to_string());
+    } else {
+        return HandshakeResult::err(HttpCode::Forbidden, "Unsupported WebSocket version");
+    }
+
+    if let Some(client_key) = req.headers.get("sec-websocket-key") {
+        let hash: &[u8] = &Sha1::digest(format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", client_key))[..];
+        res_headers.set("sec-websocket-accept".to_string(), base64::encode(hash));
+    } else {
+        return HandshakeResult::err(HttpCode::BadRequest, "WebSocket accept header not provided");
+    }
+
+    if let Some(origin) = req.headers.get("origin") {
+        // Policy of route group is found by path, the same way as for HTTP requests
+        let policy = app.router.match_path(&req.path).and_then(|route| route.cors.clone());
+        if !policy.as_deref().unwrap_or_else(|| CorsConfig::get()).is_origin_allowed(&origin) {
+            return HandshakeResult::err(HttpCode::Forbidden, "Origin not allowed");
+        }
+    }
+
+    let mut handshake = Handshake::new(endpoint, params, req);
+    let endpoint = app.ws_endpoints.at(endpoint).unwrap();
+    if let Some(handler) = &endpoint.on_handshake {
+        if let Err(code) = handler(req, &UrlEncoded::parse(&req.query), &mut handshake) {
+            let (_, reason) = code.get_description();
+            let reason = reason.to_string();
+            return HandshakeResult::err(code, &reason);
+        }
+    }
+
+    if let Some(protocol) = handshake.protocol() {
+        res_headers.set("sec-websocket-protocol".to_string(), protocol.to_string());
+    }
+
+    if endpoint.deflate {
+        // Offers can be split between several headers
+        let offers = req.headers.get_all("sec-websocket-extensions").collect::<Vec<_>>().join(",");
+        if let Some(extension) = deflate::negotiate(&offers, &Config::get().websocket.deflate) {
+            res_headers.set("sec-websocket-extensions".to_string(), extension);
+            handshake.is_deflate = true;
+        }
+    }
+
+    return HandshakeResult::ok(handshake, res_headers);
+}
+
+/// Blocking loop of socket, thread waits for frames by read timeout.
+/// Returns error that ended the socket, `Ok` if it was closed by any side.
+pub fn maintain_websocket (app: &App, mut ctx: SocketContext, endpoint_index: usize) -> Result<(), io::Error> {
+    let mut socket = SocketLoop::new(app, endpoint_index);
+    socket.open(&mut ctx);
+
+    loop {
+        if !socket.check(&mut ctx) { break; }
+        if let Err(err) = ctx.stream.get_ref().set_read_timeout(socket.heartbeat.timeout()) {
+            socket.error = Some(err);
+            break;
+        }
+
+        match ctx.stream.read_message() {
+            // Read timeout, deadlines are checked on the next iteration
+            Err(Error::Io(err)) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {},
+            result => if !socket.receive(&mut ctx, result) { break; }
+        }
+    }
+
+    return socket.close(&mut ctx);
+}
+
+/// Async loop of socket, thread is occupied only by handlers, they are blocking
+#[cfg(feature = "async")]
+pub(crate) async fn maintain_websocket_async (app: &App, mut ctx: SocketContext, endpoint_index: usize) -> Result<(), io::Error> {
+    let mut socket = SocketLoop::new(app, endpoint_index);
+    block_in_place(|| socket.open(&mut ctx));
+
+    loop {
+        if !socket.check(&mut ctx) { break; }
+
+        match ctx.stream.read_message() {
+            // No complete message yet, socket is waited until data or the nearest deadline
+            Err(Error::Io(err)) if err.kind() == ErrorKind::WouldBlock => {
+                let readable = ctx.stream.get_ref().readable();
+                let result = match socket.heartbeat.timeout() {
+                    Some(timeout) => tokio::time::timeout(timeout, readable).await.unwrap_or(Ok(())),
+                    None => readable.await
+                };
+
+                if let Err(err) = result {
+                    if !block_in_place(|| socket.receive(&mut ctx, Err(Error::Io(err)))) { break; }
+                }
+            },
+            result => if !block_in_place(|| socket.receive(&mut ctx, result)) { break; }
+        }
+    }
+
+    return block_in_place(|| socket.close(&mut ctx));
+}
+
+/// Heartbeat and handlers of socket, shared by blocking and async loops
+struct SocketLoop<'a> {
+    app: &'a App,
+    endpoint: &'a WebSocketEndpoint,
+    endpoint_index: usize,
+    heartbeat: Heartbeat<'static>,
+    /// Code and reason passed to `close` handler
+    close: (u16, String),
+    /// Error that ended the socket
+    error: Option<io::Error>
+}
+
+impl<'a> SocketLoop<'a> {
+    fn new (app: &'a App, endpoint_index: usize) -> Self {
+        SocketLoop {
+            app,
+            endpoint: app.ws_endpoints.at(endpoint_index).unwrap(),
+            endpoint_index,
+            heartbeat: Heartbeat::new(&Config::get().websocket),
+            // Connection closed without close frame
+            close: (u16::from(CloseCode::Abnormal), String::new()),
+            error: None
+        }
+    }
+
+    fn open (&mut self, ctx: &mut SocketContext) {
+        if let Some(handler) = &self.endpoint.on_open {
+            call_handler("open", || handler(ctx));
+        }
+    }
+
+    /// Sends ping or closes socket when deadline passes, returns `false` if socket is lost
+    fn check (&mut self, ctx: &mut SocketContext) -> bool {
+        // Handler could start closing handshake by `ctx.end`
+        if ctx.is_closed() { self.heartbeat.closing(); }
+
+        match self.heartbeat.check() {
+            HeartbeatAction::Wait => {},
+            HeartbeatAction::Ping => {
+                let _ = ctx.sender().send(Message::Ping(Vec::new()));
+            },
+            HeartbeatAction::Idle => {
+                let _ = ctx.end(u16::from(CloseCode::Normal), "Idle timeout");
+                self.heartbeat.closing();
+            },
+            HeartbeatAction::Lost => {
+                if !self.heartbeat.is_closing() {
+                    self.close.1 = "Pong timeout".to_string();
+                    self.error = Some(io::Error::new(ErrorKind::TimedOut, "Pong timeout"));
+                }
+
+                return false;
+            }
+        }
+
+        return true;
+    }
+
+    /// Handles result of read, returns `false` if socket can't be read anymore
+    fn receive (&mut self, ctx: &mut SocketContext, result: Result<Message, Error>) -> bool {
+        match result {
+            Ok(Message::Close(frame)) => {
+                self.heartbeat.seen(false);
+                // Reading continues until tungstenite completes closing handshake
+                self.close = match frame {
+                    Some(frame) => (u16::from(frame.code), frame.reason.into_owned()),
+                    None => (u16::from(CloseCode::Status), String::new())
+                };
+            }
+            Ok(msg) => {
+                self.heartbeat.seen(msg.is_text() || msg.is_binary());
+                dispatch_websocket_message(self.app, ctx, msg, self.endpoint_index);
+            },
+            Err(Error::ConnectionClosed | Error::AlreadyClosed) => return false,
+            Err(err) => {
+                if let Some(handler) = &self.endpoint.on_error {
+                    call_handler("error", || handler(ctx, &err));
+                } else {
+                    log_error_lines("WebSocket error", err.to_string());
+                }
+
+                // Socket can't be used after any other error
+                self.error = Some(into_io_error(err));
+                return false;
+            }
+        }
+
+        return true;
+    }
+
+    fn close (&mut self, ctx: &mut SocketContext) -> Result<(), io::Error> {
+        ctx.sender().set_closed();
+        if let Some(handler) = &self.endpoint.on_close {
+            call_handler("close", || handler(ctx, self.close.0, &self.close.1));
+        }
+
+        return match self.error.take() {
+            Some(err) => Err(err),
+            None => Ok(())
+        };
+    }
+}
+
+/// Panic in `open`, `close` or `error` handler is logged, socket is served or closed as usual
+fn call_handler<F: FnOnce()> (name: &str, handler: F) {
+    if catch_unwind(AssertUnwindSafe(handler)).is_err() {
+        log_error_lines("WebSocket handler failed", format!("Panic in {} handler", name));
+    }
+}
+
+pub fn dispatch_websocket_message (app: &App, ctx: &mut SocketContext, msg: Message, endpoint_index: usize) {
+    let endpoint = app.ws_endpoints.at(endpoint_index).unwrap();
+    // Ping, pong and close frames are handled by tungstenite and `maintain_websocket`.
+    // Reply write errors are ignored, lost connection is detected by the next read.
+    match msg {
+        Message::Text(content) => {
+            let (head, payload) = split_socket_message(&content);
+            // `event#id:[args]` requests acknowledgement
+            let (event_name, request_id) = match head.split_once('#') {
+                Some((event_name, request_id)) => (event_name, Some(request_id)),
+                None => (head, None)
+            };
+
+            let result = match endpoint.handlers.get(event_name) {
+                Some(handler) => SocketArgs::parse(payload).and_then(|args| handler.call(ctx, args)),
+                None => Err(SocketError::new("unknown_event", &format!("Event {} not defined", event_name)))
+            };
+
+            let _ = match (request_id, result) {
+                (Some(request_id), Ok(value)) => ctx.emit(&format!("ack#{}", request_id), json::array![value]),
+                (Some(request_id), Err(error)) => ctx.emit(&format!("error#{}", request_id), error.with_event(event_name).to_json()),
+                (None, Err(error)) => ctx.error(&error.with_event(event_name)),
+                (None, Ok(_)) => Ok(())
+            };
+        },
+        Message::Binary(data) => {
+            let result = match &endpoint.on_binary {
+                Some(handler) => match catch_unwind(AssertUnwindSafe(|| handler(ctx, data))) {
+                    Ok(result) => result,
+                    Err(_) => Err(SocketError::new("internal_error", "Binary handler failed"))
+                },
+                None => Err(SocketError::new("unsupported", "Binary messages are not accepted"))
+            };
+
+            if let Err(error) = result {
+                let _ = ctx.error(&error);
+            }
+        },
+        _ => {}
+    }
+}
+
+fn split_socket_message (payload: &str) -> (&str, &str) {
+    if let Some(delim_index) = payload.find(':') {
+        return (&payload[0..delim_index], &payload[delim_index+1..]);
+    } else {
+        return (payload, "");
+    }
+}
+
+pub struct WebSocketEndpoints(Vec<WebSocketEndpoint>);
+impl WebSocketEndpoints {
+    pub fn empty () -> Self { WebSocketEndpoints(Vec::new()) }
+
+    /// Finds endpoint by pattern it was registered with
+    pub fn get (&self, path: &str) -> Option<&WebSocketEndpoint> {
+        return self.0.iter().find(|endpoint| endpoint.path == path);
+    }
+
+    pub fn get_pair (&self, path: &str) -> Option<(usize, &WebSocketEndpoint)> {
+        return self.0.iter().enumerate().find(|(_, endpoint)| endpoint.path == path);
+    }
+
+    /// Finds first endpoint which pattern matches request path, returns its index and path params
+    pub fn match_path (&self, path: &str) -> Option<(usize, HashMap<String, String>)> {
+        return self.0.iter().enumerate().find_map(|(index, endpoint)| Some((index, endpoint.matcher.exec(path)?)));
+    }
+
+    pub fn at (&self, index: usize) -> Option<&WebSocketEndpoint> {
+        return self.0.get(index);
+    }
+
+    /// Registers handler of `event:[args]` messages.
+    /// Returned value is sent back as `ack#id:[value]` if client sent `event#id:[args]`,
+    /// returned error is sent as `error#id:[error]` or `error:[error]` without id.
+    pub fn register<Reply, Caller> (&mut self, path: &str, event: &str, method: Caller)
+    where Reply: IntoReply, Caller: Fn(&mut SocketContext, SocketArgs) -> Reply + Sync + Send + 'static {
+        let method = move |ctx: &mut SocketContext, args: SocketArgs| method(ctx, args).into_reply();
+        let handler = SocketEventHandler { event: event.to_string(), method: Arc::new(method) };
+        self.endpoint_mut(path).handlers.push(handler);
+    }
+
+    /// Enables `permessage-deflate` for endpoint, it's used only if client offers it
+    pub fn enable_deflate (&mut self, path: &str) {
+        self.endpoint_mut(path).deflate = true;
+    }
+
+    /// Registers handler that can reject handshake with HTTP code, select subprotocol and attach extensions.
+    /// Handler gets parsed query of the request too.
+    pub fn on_handshake<Caller: Fn(&Request, &UrlEncoded, &mut Handshake) -> Result<(), HttpCode> + Sync + Send + 'static> (&mut self, path: &str, method: Caller) {
+        self.endpoint_mut(path).on_handshake = Some(Arc::new(method));
+    }
+
+    /// Registers handler called right after handshake, before any event is dispatched
+    pub fn on_open<Caller: Fn(&mut SocketContext) + Sync + Send + 'static> (&mut self, path: &str, method: Caller) {
+        self.endpoint_mut(path).on_open = Some(Arc::new(method));
+    }
+
+    /// Registers handler called with close code and reason when socket is closed for any reason,
+    /// `1006` means that connection was lost without close frame
+    pub fn on_close<Caller: Fn(&mut SocketContext, u16, &str) + Sync + Send + 'static> (&mut self, path: &str, method: Caller) {
+        self.endpoint_mut(path).on_close = Some(Arc::new(method));
+    }
+
+    /// Registers handler of protocol and IO errors, `close` handler is called after it
+    pub fn on_error<Caller: Fn(&mut SocketContext, &Error) + Sync + Send + 'static> (&mut self, path: &str, method: Caller) {
+        self.endpoint_mut(path).on_error = Some(Arc::new(method));
+    }
+
+    /// Registers handler of binary messages, returned error is sent as `error:[error]`.
+    /// Binary messages are answered with error if endpoint has no such handler.
+    pub fn on_binary<Reply, Caller> (&mut self, path: &str, method: Caller)
+    where Reply: IntoReply, Caller: Fn(&mut SocketContext, Vec<u8>) -> Reply + Sync + Send + 'static {
+        let method = move |ctx: &mut SocketContext, data: Vec<u8>| method(ctx, data).into_reply().map(|_| ());
+        self.endpoint_mut(path).on_binary = Some(Arc::new(method));
+    }
+
+    fn endpoint_mut (&mut self, path: &str) -> &mut WebSocketEndpoint {
+        let index = match self.get_pair(path) {
+            Some((index, _)) => index,
+            None => {
+                self.0.push(WebSocketEndpoint::empty(path));
+                self.0.len() - 1
+            }
+        };
+
+        return &mut self.0[index];
+    }
+}
+
+pub struct WebSocketEndpoint {
+    /// Pattern of the endpoint, same as in HTTP routes: `/rooms/{id}`
+    pub path: String,
+    pub matcher: PathMatcher,
+    pub handlers: WebSocketHandlers,
+    /// Compression is offered to clients
+    pub deflate: bool,
+    pub on_handshake: Option<Arc<HandshakeCallerType>>,
+    pub on_open: Option<Arc<OpenCallerType>>,
+    pub on_close: Option<Arc<CloseCallerType>>,
+    pub on_error: Option<Arc<ErrorCallerType>>,
+    pub on_binary: Option<Arc<BinaryCallerType>>
+}
+
+impl WebSocketEndpoint {
+    pub fn new (path: &str, handler: SocketEventHandler) -> Self {
+        let mut endpoint = WebSocketEndpoint::empty(path);
+        endpoint.handlers.push(handler);
+        return endpoint;
+    }
+
+    pub fn empty (path: &str) -> Self {
+        WebSocketEndpoint {
+            path: path.to_string(),
+            matcher: PathMatcher::from_pattern(path.to_string()),
+            handlers: WebSocketHandlers(Vec::new()),
+            deflate: false,
+            on_handshake: None,
+            on_open: None,
+            on_close: None,
+            on_error: None,
+            on_binary: None
+        }
+    }
+}
+
+pub struct WebSocketHandlers(Vec<SocketEventHandler>);
+
+impl WebSocketHandlers {
+    pub fn get (&self, name: &str) -> Option<&SocketEventHandler> {
+        return self.0.iter().find(|handler| handler.event == name);
+    }
+
+    #[inline]
+    pub fn push (&mut self, handler: SocketEventHandler) {
+        self.0.push(handler)
+    }
+}
+
+pub struct SocketEventHandler {
+    pub event: String,
+    pub method: Arc<EventCallerType>
+}
+
+impl SocketEventHandler {
+    /// Panic in handler is reported as error, socket stays usable
+    pub fn call (&self, ctx: &mut SocketContext, args: SocketArgs) -> Result<JsonValue, SocketError> {
+        return match catch_unwind(AssertUnwindSafe(|| (self.method)(ctx, args))) {
+            Ok(result) => result,
+            Err(_) => Err(SocketError::new("internal_error", "Event handler failed"))
+        };
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::net::{Shutdown, TcpStream};
+use std::sync::{Arc, Condvar, Mutex, atomic::{AtomicBool, Ordering}};
+use std::thread;
+use std::time::Duration;
+
+/// Frames waiting to be written to socket by its writer, so senders never wait for client.
+/// Client that doesn't read them fast enough is disconnected instead of buffering without limit.
+pub(crate) struct Outbox {
+    state: Mutex<OutboxState>,
+    /// Maximum size of queued frames in bytes
+    limit: usize,
+    /// Handle used to disconnect slow client, it's set by writer
+    socket: Mutex<Option<TcpStream>>,
+    /// Socket was closed by any side, messages can't be sent anymore
+    is_closed: AtomicBool,
+    /// Wakes writer thread of blocking engine
+    ready: Condvar,
+    /// Wakes writer task of async engine
+    #[cfg(feature = "async")]
+    notify: tokio::sync::Notify
+}
+
+struct OutboxState {
+    frames: VecDeque<Vec<u8>>,
+    /// Size of queued frames in bytes
+    size: usize,
+    /// Close frame was queued, nothing can be written after it
+    is_closing: bool,
+    /// Socket isn't read anymore, writer exits when queue is empty
+    is_ended: bool
+}
+
+impl Outbox {
+    pub fn new (limit: usize) -> Self {
+        Outbox {
+            state: Mutex::new(OutboxState { frames: VecDeque::new(), size: 0, is_closing: false, is_ended: false }),
+            limit,
+            socket: Mutex::new(None),
+            is_closed: AtomicBool::new(false),
+            ready: Condvar::new(),
+            #[cfg(feature = "async")]
+            notify: tokio::sync::Notify::new()
+        }
+    }
+
+    #[inline]
+    pub fn is_closed (&self) -> bool {
+        return self.is_closed.load(Ordering::SeqCst);
+    }
+
+    #[inline]
+    pub fn set_closed (&self) {
+        self.is_closed.store(true, Ordering::SeqCst);
+    }
+
+    /// Queues formatted frame, returns `false` if it can't be written anymore.
+    /// Frames after close frame are dropped, e.g. reply of tungstenite to close frame of client.
+    pub fn push (&self, frame: Vec<u8>) -> bool {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.is_closing || state.is_ended { return false; }
+
+            // Frame bigger than limit is still accepted by empty queue
+            if state.size != 0 && state.size + frame.len() > self.limit {
+                drop(state);
+                self.disconnect();
+                return false;
+            }
+
+            state.size += frame.len();
+            state.is_closing = is_close_frame(&frame);
+            state.frames.push_back(frame);
+        }
+
+        self.wake();
+        return true;
+    }
+
+    /// Writer exits after frames that are already queued
+    pub fn end (&self) {
+        self.set_closed();
+        self.state.lock().unwrap().is_ended = true;
+        self.wake();
+    }
+
+    /// Socket can't be written, queued frames are dropped
+    fn fail (&self) {
+        self.set_closed();
+        let mut state = self.state.lock().unwrap();
+        state.frames.clear();
+        state.size = 0;
+        state.is_ended = true;
+    }
+
+    /// Closes connection of slow client, so its reader and writer stop too
+    fn disconnect (&self) {
+        self.fail();
+        if let Some(socket) = &*self.socket.lock().unwrap() {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+
+        self.wake();
+    }
+
+    fn set_socket (&self, socket: Option<TcpStream>) {
+        *self.socket.lock().unwrap() = socket;
+    }
+
+    /// Takes all queued frames
+    fn take (state: &mut OutboxState) -> Vec<Vec<u8>> {
+        state.size = 0;
+        return state.frames.drain(..).collect();
+    }
+
+    /// Blocks until frames are queued, `None` if socket is ended
+    fn wait (&self) -> Option<Vec<Vec<u8>>> {
+        let mut state = self.state.lock().unwrap();
+        while state.frames.is_empty() && !state.is_ended {
+            state = self.ready.wait(state).unwrap();
+        }
+
+        return if state.frames.is_empty() { None } else { Some(Outbox::take(&mut state)) };
+    }
+
+    #[cfg(feature = "async")]
+    async fn next (&self) -> Option<Vec<Vec<u8>>> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if !state.frames.is_empty() { return Some(Outbox::take(&mut state)); }
+                if state.is_ended { return None; }
+            }
+
+            // Notification sent before waiting is kept, so it isn't lost
+            self.notify.notified().await;
+        }
+    }
+
+    fn wake (&self) {
+        self.ready.notify_one();
+        #[cfg(feature = "async")]
+        self.notify.notify_one();
+    }
+}
+
+/// Tungstenite writes every frame by one call, so opcode is in the first byte
+fn is_close_frame (frame: &[u8]) -> bool {
+    return matches!(frame.first(), Some(byte) if byte & 0x0f == 0x08);
+}
+
+/// Writes frames of socket served by blocking engine, write is limited by `timeout`
+pub(crate) fn spawn_writer (outbox: Arc<Outbox>, mut stream: TcpStream, timeout: Duration) {
+    outbox.set_socket(stream.try_clone().ok());
+    thread::spawn(move || {
+        if stream.set_write_timeout(Some(timeout)).is_err() { outbox.fail(); }
+
+        while let Some(frames) = outbox.wait() {
+            if frames.iter().try_for_each(|frame| stream.write_all(frame)).is_err() {
+                outbox.fail();
+            }
+        }
+
+        // Server closes connection first, it also wakes reader if writing failed
+        let _ = stream.shutdown(Shutdown::Both);
+        outbox.set_socket(None);
+    });
+}
+
+/// Writes frames of socket served by async engine, `socket` is blocking handle used to close connection
+#[cfg(feature = "async")]
+pub(crate) fn spawn_async_writer (outbox: Arc<Outbox>, stream: Arc<tokio::net::TcpStream>, socket: TcpStream, timeout: Duration) {
+    outbox.set_socket(socket.try_clone().ok());
+    tokio::spawn(async move {
+        while let Some(frames) = outbox.next().await {
+            for frame in frames {
+                if !matches!(tokio::time::timeout(timeout, write_all(&stream, &frame)).await, Ok(Ok(_))) {
+                    outbox.fail();
+                    break;
+                }
+            }
+        }
+
+        let _ = socket.shutdown(Shutdown::Both);
+        outbox.set_socket(None);
+    });
+}
+
+/// Stream is shared with reader, so it's written through readiness instead of `AsyncWrite`
+#[cfg(feature = "async")]
+async fn write_all (stream: &tokio::net::TcpStream, mut buf: &[u8]) -> Result<(), std::io::Error> {
+    while !buf.is_empty() {
+        stream.writable().await?;
+        match stream.try_write(buf) {
+            Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+            Ok(len) => buf = &buf[len..],
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {},
+            Err(err) => return Err(err)
+        }
+    }
+
+    return Ok(());
+}
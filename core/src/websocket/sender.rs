@@ -0,0 +1,243 @@
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use std::sync::Arc;
+use bufstream::BufStream;
+use json::JsonValue;
+use tungstenite::{Message, protocol::{CloseFrame, frame::coding::CloseCode}};
+use crate::app::config::Config;
+use super::{args::SocketError, deflate::{self, Inflater}, outbox::{self, Outbox}};
+use tungstenite::protocol::frame::{Frame, coding::{OpCode, Data}};
+
+/// Queues frames of socket, they are written by writer of the socket.
+/// Clones share the same queue and can be used from other threads.
+#[derive(Clone)]
+pub struct SocketSender {
+    outbox: Arc<Outbox>,
+    /// `permessage-deflate` was negotiated
+    is_deflate: bool
+}
+
+impl SocketSender {
+    pub(crate) fn new (is_deflate: bool) -> Self {
+        SocketSender { outbox: Arc::new(Outbox::new(Config::get().websocket.send_queue)), is_deflate }
+    }
+
+    /// Starts writer thread of socket served by blocking engine
+    pub(crate) fn spawn_writer (&self, stream: TcpStream) {
+        outbox::spawn_writer(self.outbox.clone(), stream, Config::get().websocket.pong_timeout);
+    }
+
+    /// Starts writer task of socket served by async engine, `socket` is used to close connection
+    #[cfg(feature = "async")]
+    pub(crate) fn spawn_async_writer (&self, stream: Arc<tokio::net::TcpStream>, socket: TcpStream) {
+        outbox::spawn_async_writer(self.outbox.clone(), stream, socket, Config::get().websocket.pong_timeout);
+    }
+
+    /// `true` after socket was closed by any side, sending fails then
+    pub fn is_closed (&self) -> bool {
+        return self.outbox.is_closed();
+    }
+
+    pub(crate) fn set_closed (&self) {
+        self.outbox.set_closed();
+    }
+
+    /// Starts closing handshake from outside of socket handlers, messages can't be sent after it
+    pub(crate) fn close (&self, code: u16, reason: &str) {
+        let frame = CloseFrame { code: CloseCode::from(code), reason: reason.to_string().into() };
+        if let Ok(frame) = format_frame(Message::Close(Some(frame)), false) {
+            let _ = self.send_raw(&frame);
+        }
+
+        self.set_closed();
+    }
+
+    /// Socket isn't maintained anymore, writer exits after queued frames
+    pub(crate) fn end (&self) {
+        self.outbox.end();
+    }
+
+    pub fn send (&self, msg: Message) -> Result<(), Error> {
+        return self.send_raw(&format_frame(msg, self.is_deflate)?);
+    }
+
+    #[inline]
+    pub fn binary (&self, data: Vec<u8>) -> Result<(), Error> {
+        return self.send(Message::Binary(data));
+    }
+
+    /// Sends event in `event:["message"]` format
+    #[inline]
+    pub fn text (&self, event: &str, message: &str) -> Result<(), Error> {
+        return self.send(Message::text(format_event(event, message)));
+    }
+
+    /// Sends event with arguments, value that isn't array is sent as single argument
+    #[inline]
+    pub fn emit (&self, event: &str, args: JsonValue) -> Result<(), Error> {
+        return self.send(Message::text(format_args_event(event, args)));
+    }
+
+    #[inline]
+    pub fn error (&self, error: &SocketError) -> Result<(), Error> {
+        return self.emit("error", error.to_json());
+    }
+
+    #[inline]
+    pub(crate) fn send_prepared (&self, msg: &PreparedMessage) -> Result<(), Error> {
+        match (&msg.deflated, self.is_deflate) {
+            (Some(deflated), true) => self.send_raw(deflated),
+            _ => self.send_raw(&msg.plain)
+        }
+    }
+
+    /// Queues already formatted frame, so broadcasts are serialized once.
+    /// Frame is written later, write errors close the socket.
+    pub(crate) fn send_raw (&self, frame: &[u8]) -> Result<(), Error> {
+        if self.is_closed() || !self.outbox.push(frame.to_vec()) {
+            return Err(Error::new(ErrorKind::NotConnected, "Socket is closed"));
+        }
+
+        return Ok(());
+    }
+}
+
+/// Message formatted once for many sockets, compressed frame is made only if some socket needs it
+pub(crate) struct PreparedMessage {
+    plain: Vec<u8>,
+    deflated: Option<Vec<u8>>
+}
+
+impl PreparedMessage {
+    pub fn new (msg: Message, senders: &[SocketSender]) -> Result<Self, Error> {
+        let deflated = match senders.iter().any(|sender| sender.is_deflate) {
+            true => Some(format_frame(msg.clone(), true)?),
+            false => None
+        };
+
+        return Ok(PreparedMessage { plain: format_frame(msg, false)?, deflated });
+    }
+}
+
+pub(crate) fn format_frame (msg: Message, is_deflate: bool) -> Result<Vec<u8>, Error> {
+    let frame = match msg {
+        Message::Text(text) => data_frame(text.into_bytes(), Data::Text, is_deflate)?,
+        Message::Binary(data) => data_frame(data, Data::Binary, is_deflate)?,
+        Message::Ping(data) => Frame::ping(data),
+        Message::Pong(data) => Frame::pong(data),
+        Message::Close(frame) => Frame::close(frame),
+        Message::Frame(frame) => frame
+    };
+
+    let mut buffer = Vec::with_capacity(frame.len());
+    frame.format(&mut buffer).map_err(into_io_error)?;
+    return Ok(buffer);
+}
+
+fn data_frame (data: Vec<u8>, kind: Data, is_deflate: bool) -> Result<Frame, Error> {
+    let config = &Config::get().websocket.deflate;
+    if is_deflate && data.len() >= config.threshold {
+        let mut frame = Frame::message(deflate::compress(&data, config.window_bits)?, OpCode::Data(kind), true);
+        // RSV1 marks compressed message
+        frame.header_mut().rsv1 = true;
+        return Ok(frame);
+    }
+
+    return Ok(Frame::message(data, OpCode::Data(kind), true));
+}
+
+/// Protocol errors are wrapped, so every send method returns `std::io::Error`
+pub(crate) fn into_io_error (err: tungstenite::Error) -> Error {
+    match err {
+        tungstenite::Error::Io(err) => err,
+        tungstenite::Error::ConnectionClosed | tungstenite::Error::AlreadyClosed => Error::new(ErrorKind::NotConnected, "Socket is closed"),
+        err => Error::other(err.to_string())
+    }
+}
+
+pub(crate) fn format_event (event: &str, message: &str) -> String {
+    return format_args_event(event, json::array![message]);
+}
+
+pub(crate) fn format_args_event (event: &str, args: JsonValue) -> String {
+    if args.is_array() {
+        return format!("{}:{}", event, args.dump());
+    } else {
+        return format!("{}:{}", event, json::array![args].dump());
+    }
+}
+
+/// Reading side of socket
+pub(crate) enum SocketReader {
+    /// Blocking stream, waiting is limited by read timeout
+    Blocking(BufStream<TcpStream>),
+    /// Stream of async engine, reads fail with `WouldBlock` until it's readable
+    #[cfg(feature = "async")]
+    Async(Arc<tokio::net::TcpStream>)
+}
+
+impl Read for SocketReader {
+    fn read (&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        return match self {
+            SocketReader::Blocking(reader) => reader.read(buf),
+            #[cfg(feature = "async")]
+            SocketReader::Async(stream) => stream.try_read(buf)
+        };
+    }
+}
+
+/// Stream of `WebSocket`, writes go through the shared sender so frames never interleave
+pub struct SocketStream {
+    reader: SocketReader,
+    writer: SocketSender,
+    /// Decompresses incoming messages if `permessage-deflate` was negotiated
+    inflater: Option<Inflater>
+}
+
+impl SocketStream {
+    pub(crate) fn new (reader: SocketReader, writer: SocketSender, inflater: Option<Inflater>) -> Self {
+        SocketStream { reader, writer, inflater }
+    }
+
+    /// Only blocking stream is limited, async one never waits
+    pub(crate) fn set_read_timeout (&self, timeout: Option<Duration>) -> Result<(), Error> {
+        return match &self.reader {
+            SocketReader::Blocking(reader) => reader.get_ref().set_read_timeout(timeout),
+            #[cfg(feature = "async")]
+            SocketReader::Async(_) => Ok(())
+        };
+    }
+
+    /// Waits until async stream can be read
+    #[cfg(feature = "async")]
+    pub(crate) async fn readable (&self) -> Result<(), Error> {
+        return match &self.reader {
+            SocketReader::Blocking(_) => Ok(()),
+            SocketReader::Async(stream) => stream.readable().await
+        };
+    }
+}
+
+impl Read for SocketStream {
+    #[inline]
+    fn read (&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        return match &mut self.inflater {
+            Some(inflater) => inflater.read(&mut self.reader, buf),
+            None => self.reader.read(buf)
+        };
+    }
+}
+
+impl Write for SocketStream {
+    /// Tungstenite writes whole frames at once and never waits for writer.
+    /// Frames after close frame are dropped, so they aren't treated as errors.
+    fn write (&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.writer.outbox.push(buf.to_vec());
+        return Ok(buf.len());
+    }
+
+    fn flush (&mut self) -> Result<(), Error> {
+        return Ok(());
+    }
+}
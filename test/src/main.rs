@@ -1,59 +1,301 @@
-use std::sync::{Mutex, MutexGuard};
-use dc_api_core::{app::{App, config::config_path}, http::codes::HttpCode};
+use std::time::{Duration, Instant};
+use json::JsonValue;
+use dc_api_core::{app::{App, config::config_path, middleware::Flow}, context::http::RequestData, http::{codes::HttpCode, cookie::{Cookie, SameSite}, cors::CorsConfig, entity::{HttpMethod, Response}}, session::{SessionData, Sessions}, websocket::args::SocketError};
 
-static mut APP: Option<Mutex<App>> = None;
-#[inline(always)]
-pub fn get_app_mutex () -> &'static mut Mutex<App> {
-    unsafe { APP.as_mut().unwrap() }
+struct User {
+    name: String
 }
-#[inline(always)]
-pub fn get_app () -> MutexGuard<'static, App> {
-    get_app_mutex().lock().unwrap()
+
+/// Session of demo routes
+struct Visits {
+    count: u32
+}
+
+impl SessionData for Visits {
+    fn empty () -> Self { Visits { count: 0 } }
+    fn from_json (value: &JsonValue) -> Option<Self> { Some(Visits { count: value["count"].as_u32()? }) }
+    fn to_json (&self) -> JsonValue { json::object! { count: self.count } }
 }
 
+/// Count of events sent by socket
+#[derive(Default)]
+struct EventCount(u32);
+
 fn main () {
-    unsafe {
-        let app = App::new();
-        APP = Some(Mutex::new(app));
-    }
+    let mut app = App::new();
 
-    {
-        println!("{}", config_path("hello"));
+    println!("{}", config_path("hello"));
 
-        let mut app = get_app();
-        app.router.register("/test-endpoint/ctx".to_string(), |ctx| {
+    app.router.group("/test-endpoint", |g| {
+        g.register("/ctx".to_string(), |ctx| {
             let msg = format!("{:#?}", ctx);
             return ctx.text(&msg);
         });
 
-        app.router.register("/test-endpoint/ip".to_string(), |ctx| {
+        g.register("/ip".to_string(), |ctx| {
             let msg = format!("{:?}", ctx.address);
             return ctx.text(&msg);
         });
 
-        app.router.register("/test-endpoint/headers".to_string(), |mut ctx| {
+        g.register("/headers".to_string(), |mut ctx| {
             let hostname = ctx.get_header_default("host", "none".to_string());
             ctx.set_header("x-echo-host", hostname);
             return ctx.text("Check headers!");
         });
 
-        app.router.register("/test-endpoint/{sup}-{sub}".to_string(), |ctx| {
+        g.register("/{sup}-{sub}".to_string(), |ctx| {
             let msg = format!("{:?}", ctx.params);
             return ctx.text(&msg);
         });
 
-        app.router.register("/test-endpoint/404".to_string(), |ctx| {
+        g.register("/404".to_string(), |ctx| {
             return ctx.text_status("Nothing there!", HttpCode::NotFound);
         });
 
-        app.router.register("/test-endpoint/redirect".to_string(), |ctx| {
+        g.register("/redirect".to_string(), |ctx| {
             return ctx.redirect("./redirected");
         });
 
-        app.ws_endpoints.register("/socket", "test-event", |ctx| {
-            ctx.text("reply", "Event handled!");
+        g.register("/query".to_string(), |ctx| {
+            let page: u32 = match ctx.query.get_as("page") {
+                Ok(value) => value.unwrap_or(1),
+                Err(code) => return Response::from_code(code, "Invalid page number")
+            };
+
+            let msg = format!("page={} ids={:?}", page, ctx.query.get_all("ids"));
+            return ctx.text(&msg);
+        });
+
+        g.register("/upload".to_string(), |ctx| {
+            let multipart = match ctx.multipart() {
+                Ok(value) => value,
+                Err(code) => return Response::from_status(code)
+            };
+
+            let mut msg = format!("{:?}\n", multipart.fields);
+            for file in &multipart.files {
+                msg += &format!("{} {} {} {} {:?}\n", file.name, file.filename, file.content_type, file.size, file.path());
+            }
+
+            return ctx.text(&msg);
+        });
+
+        g.get("/item/{id}".to_string(), |ctx| {
+            let msg = format!("Item #{}", ctx.params["id"]);
+            return ctx.text(&msg);
         });
-    }
 
-    dc_api_core::spawn_server(get_app_mutex());
+        g.delete("/item/{id}".to_string(), |ctx| {
+            let msg = format!("Item #{} deleted", ctx.params["id"]);
+            return ctx.text(&msg);
+        });
+
+        g.on(HttpMethod::OPTIONS, "/item/{id}".to_string(), |mut ctx| {
+            ctx.set_header("allow", "GET, DELETE, OPTIONS".to_string());
+            let msg = format!("Item #{} supports GET and DELETE", ctx.params["id"]);
+            return ctx.text(&msg);
+        });
+
+        g.get("/export".to_string(), |ctx| {
+            let rows: usize = ctx.query.get_as("rows").unwrap_or(None).unwrap_or(10);
+            return ctx.stream("text/csv", move |writer| {
+                writer.write_all(b"id,square\n")?;
+                for i in 0..rows {
+                    writer.write_all(format!("{},{}\n", i, i * i).as_bytes())?;
+                }
+
+                return Ok(());
+            });
+        });
+
+        g.register("/echo".to_string(), |ctx| {
+            return match ctx.data() {
+                Ok(RequestData::Json(value)) => ctx.json(&value),
+                Ok(data) => {
+                    let msg = format!("{:?}", data);
+                    ctx.text(&msg)
+                }
+                Err(code) => Response::from_status(code)
+            };
+        });
+
+        g.on_async(HttpMethod::GET, "/delay/{ms}".to_string(), |ctx| async move {
+            let ms: u64 = ctx.params.get("ms").and_then(|ms| ms.parse().ok()).unwrap_or(0);
+            tokio::time::sleep(std::time::Duration::from_millis(ms)).await;
+            return ctx.text(&format!("Waited {}ms", ms));
+        });
+    });
+
+    app.ws_endpoints.register("/socket", "test-event", |ctx, _args| -> Result<(), SocketError> {
+        ctx.text("reply", "Event handled!")?;
+        return Ok(());
+    });
+
+    app.ws_endpoints.enable_deflate("/socket");
+
+    app.ws_endpoints.on_open("/socket", |ctx| {
+        let _ = ctx.text("welcome", &ctx.http.address.to_string());
+    });
+
+    app.ws_endpoints.on_close("/socket", |ctx, code, reason| {
+        println!("Socket {} closed with {} {}", ctx.http.address, code, reason);
+        if ctx.is_subscribed("lobby") {
+            ctx.broadcast("lobby", "left", &ctx.http.address.to_string());
+        }
+    });
+
+    app.ws_endpoints.on_error("/socket", |ctx, error| {
+        println!("Socket {} failed: {}", ctx.http.address, error);
+    });
+
+    app.ws_endpoints.register("/socket", "join", |ctx, _args| -> Result<(), SocketError> {
+        ctx.subscribe("lobby");
+        let count = ctx.channels().count("lobby");
+        ctx.text("joined", &format!("{} socket(s) in lobby", count))?;
+        return Ok(());
+    });
+
+    app.ws_endpoints.register("/socket", "shout", |ctx, args| -> Result<(), SocketError> {
+        let message = args.get::<String>(0)?;
+        ctx.broadcast("lobby", "shout", message.as_deref().unwrap_or("Hello lobby!"));
+        return Ok(());
+    });
+
+    app.ws_endpoints.register("/socket", "sum", |_ctx, args| -> Result<f64, SocketError> {
+        let numbers = args.require::<Vec<f64>>(0)?;
+        return Ok(numbers.iter().sum::<f64>());
+    });
+
+    app.ws_endpoints.register("/socket", "echo", |_ctx, args| {
+        return JsonValue::Array(args.into_vec());
+    });
+
+    app.ws_endpoints.register("/socket", "crash", |_ctx, args| {
+        if args.is_empty() { panic!("Crash requested"); }
+    });
+
+    app.ws_endpoints.register("/socket", "announce", |ctx, _args| {
+        ctx.broadcast_endpoint("announce", "Hello everyone!");
+    });
+
+    app.ws_endpoints.register("/socket", "count", |ctx, _args| {
+        let count = ctx.extensions_mut().get_or_default::<EventCount>();
+        count.0 += 1;
+        return count.0;
+    });
+
+    app.ws_endpoints.register("/socket", "bye", |ctx, _args| -> Result<(), SocketError> {
+        ctx.end(1000, "Bye")?;
+        return Ok(());
+    });
+
+    app.ws_endpoints.on_binary("/socket", |ctx, mut data| -> Result<(), SocketError> {
+        data.reverse();
+        ctx.binary(data)?;
+        return Ok(());
+    });
+
+    app.ws_endpoints.on_open("/rooms/{id}", |ctx| {
+        let room = format!("room:{}", ctx.http.params["id"]);
+        let user = ctx.http.query.get("user").unwrap_or("anonymous").to_string();
+        ctx.subscribe(&room);
+        let _ = ctx.text("room", &room);
+        ctx.broadcast(&room, "entered", &user);
+    });
+
+    app.ws_endpoints.on_handshake("/private", |_req, query, handshake| {
+        if query.get("token") != Some("secret") { return Err(HttpCode::Unauthorized); }
+
+        handshake.extensions.insert(User { name: query.get("user").unwrap_or("admin").to_string() });
+        handshake.select_protocol("chat.v1");
+        return Ok(());
+    });
+
+    app.ws_endpoints.on_open("/private", |ctx| {
+        let name = ctx.extensions().get::<User>().map(|user| user.name.clone()).unwrap_or_default();
+        let _ = ctx.emit("hello", json::array![name, ctx.protocol()]);
+    });
+
+    app.middlewares.add(|ctx| {
+        let started = Instant::now();
+        let path = ctx.req.path.clone();
+        return Flow::after(move |res| {
+            res.headers.set("x-response-time".to_string(), format!("{}us", started.elapsed().as_micros()));
+            if let HttpCode::NotFound = res.code { println!("Not found: {}", path); }
+        });
+    });
+
+    app.sessions = Some(Sessions::from_config::<Visits>());
+
+    let channels = app.channels.clone();
+    app.router.group("/test-endpoint", |g| {
+        g.group("/admin", |admin| {
+            admin.middleware(|ctx| {
+                if ctx.req.headers.get("authorization").as_deref() != Some("Bearer secret") {
+                    return Flow::Respond(Response::from_code(HttpCode::Unauthorized, "Unauthorized"));
+                }
+
+                ctx.extensions.insert(User { name: "admin".to_string() });
+                return Flow::Next;
+            });
+
+            admin.cors(CorsConfig::get().clone().origin(&["https://admin.example.com"]));
+
+            admin.get("/whoami".to_string(), |ctx| {
+                let name = ctx.extensions.get::<User>().map(|user| user.name.clone()).unwrap_or_default();
+                return ctx.text(&name);
+            });
+        });
+
+        g.get("/session".to_string(), |ctx| {
+            let count = match ctx.session::<Visits>() {
+                Some(mut visits) => {
+                    visits.count += 1;
+                    visits.count
+                },
+                None => 0
+            };
+
+            return ctx.text(&format!("Visits: {}", count));
+        });
+
+        g.post("/session/logout".to_string(), |ctx| {
+            if let Some(session) = ctx.session_handle() { session.destroy(); }
+            return Response::from_status(HttpCode::NoContent);
+        });
+
+        g.get("/cookies".to_string(), |mut ctx| {
+            let msg = format!("theme={:?} user={:?}", ctx.cookies.get("theme"), ctx.cookies.get_signed("user"));
+
+            ctx.cookies.set(Cookie::new("theme", "dark").path("/").max_age(Duration::from_secs(3600)));
+            if let Err(code) = ctx.cookies.set_signed(Cookie::new("user", "alice").path("/").http_only(true).same_site(SameSite::Strict)) {
+                return Response::from_status(code);
+            }
+
+            return ctx.text(&msg);
+        });
+
+        g.post("/cookies/clear".to_string(), |mut ctx| {
+            ctx.cookies.remove("theme");
+            ctx.cookies.remove("user");
+            return Response::from_status(HttpCode::NoContent);
+        });
+
+        g.post("/notify".to_string(), move |ctx| {
+            match ctx.body_text() {
+                Ok(text) => channels.broadcast("lobby", "notify", text),
+                Err(code) => return Response::from_status(code)
+            }
+
+            return Response::from_status(HttpCode::NoContent);
+        });
+    });
+
+    let server = if std::env::args().any(|arg| arg == "--async") {
+        dc_api_core::spawn_async_server(app)
+    } else {
+        dc_api_core::spawn_server(app)
+    };
+
+    server.wait();
 }